@@ -0,0 +1,438 @@
+//! Types to pass strings, slices, trait objects and closures between Rust and C++, and to
+//! iterate over C++ ranges.
+//!
+//! The layout of Rust references to slices and trait objects is not specified, so they cannot
+//! be captured directly. These types have a `#[repr(C)]` layout matching the C++ helpers
+//! in the `rustcpp` namespace of the generated code.
+
+use core::ffi::c_void;
+use core::fmt;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::slice;
+use core::str::{self, Utf8Error};
+
+/// A view of a `&str` or a `&[u8]`, which is a `rustcpp::str_view` in C++.
+///
+/// `rustcpp::str_view` converts to `std::string`, and to `std::string_view` in C++17.
+///
+/// ```ignore
+/// let len = cpp!(unsafe [StrView::from(name) => name as "rustcpp::str_view"] -> usize as "size_t" {
+///     return std::string(name).size();
+/// });
+/// ```
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct StrView<'a> {
+    data: *const u8,
+    len: usize,
+    _marker: PhantomData<&'a [u8]>,
+}
+
+impl<'a> StrView<'a> {
+    /// The bytes of the string, which may not be UTF-8 if it comes from C++
+    pub fn as_bytes(&self) -> &'a [u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.data, self.len) }
+    }
+
+    pub fn to_str(&self) -> Result<&'a str, Utf8Error> {
+        str::from_utf8(self.as_bytes())
+    }
+}
+
+impl<'a> From<&'a str> for StrView<'a> {
+    fn from(s: &'a str) -> Self {
+        s.as_bytes().into()
+    }
+}
+
+impl<'a> From<&'a [u8]> for StrView<'a> {
+    fn from(s: &'a [u8]) -> Self {
+        StrView {
+            data: s.as_ptr(),
+            len: s.len(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl fmt::Debug for StrView<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.to_str() {
+            Ok(s) => fmt::Debug::fmt(s, f),
+            Err(_) => fmt::Debug::fmt(self.as_bytes(), f),
+        }
+    }
+}
+
+/// A view of a `&[T]`, which is a `rustcpp::slice<const T>` in C++.
+///
+/// `rustcpp::slice` has `begin()`, `end()`, `size()` and `operator[]`.
+#[repr(C)]
+pub struct Slice<'a, T> {
+    data: *const T,
+    len: usize,
+    _marker: PhantomData<&'a [T]>,
+}
+
+impl<T> Clone for Slice<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T> Copy for Slice<'_, T> {}
+
+impl<'a, T> Slice<'a, T> {
+    pub fn as_slice(&self) -> &'a [T] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.data, self.len) }
+    }
+}
+
+impl<'a, T> From<&'a [T]> for Slice<'a, T> {
+    fn from(s: &'a [T]) -> Self {
+        Slice {
+            data: s.as_ptr(),
+            len: s.len(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Slice<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_slice(), f)
+    }
+}
+
+/// A view of a `&mut [T]`, which is a `rustcpp::slice<T>` in C++.
+#[repr(C)]
+pub struct SliceMut<'a, T> {
+    data: *mut T,
+    len: usize,
+    _marker: PhantomData<&'a mut [T]>,
+}
+
+impl<T> SliceMut<'_, T> {
+    pub fn as_slice(&self) -> &[T] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.data, self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        if self.len == 0 {
+            return &mut [];
+        }
+        unsafe { slice::from_raw_parts_mut(self.data, self.len) }
+    }
+}
+
+impl<'a, T> From<&'a mut [T]> for SliceMut<'a, T> {
+    fn from(s: &'a mut [T]) -> Self {
+        SliceMut {
+            data: s.as_mut_ptr(),
+            len: s.len(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SliceMut<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_slice(), f)
+    }
+}
+
+/// An owned C++ `std::string`, returned from C++ as a `rustcpp::string`.
+///
+/// `rustcpp::string` is constructed from a `std::string`, whose content is moved to the
+/// heap, so that it can be relocated. It is destroyed when the `CppString` is dropped.
+///
+/// ```ignore
+/// let s: String = cpp!(unsafe [] -> CppString as "rustcpp::string" {
+///     return std::to_string(42);
+/// }).into_string().unwrap();
+/// ```
+#[repr(C)]
+pub struct CppString {
+    data: *const u8,
+    len: usize,
+    owner: *mut c_void,
+    deleter: unsafe extern "C" fn(*mut c_void),
+}
+
+impl CppString {
+    /// The bytes of the string, which may not be UTF-8
+    pub fn as_bytes(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.data, self.len) }
+    }
+
+    pub fn to_str(&self) -> Result<&str, Utf8Error> {
+        str::from_utf8(self.as_bytes())
+    }
+
+    #[cfg(feature = "std")]
+    pub fn into_string(self) -> Result<std::string::String, std::string::FromUtf8Error> {
+        std::string::String::from_utf8(self.as_bytes().into())
+    }
+
+    #[cfg(feature = "std")]
+    pub fn to_string_lossy(&self) -> std::borrow::Cow<'_, str> {
+        std::string::String::from_utf8_lossy(self.as_bytes())
+    }
+}
+
+impl Deref for CppString {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl Drop for CppString {
+    fn drop(&mut self) {
+        unsafe { (self.deleter)(self.owner) }
+    }
+}
+
+impl fmt::Debug for CppString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        StrView::from(self.as_bytes()).fmt(f)
+    }
+}
+
+/// A reference to a trait object, which is a `rustcpp::dyn_ref` in C++.
+///
+/// The layout of `&dyn Trait` is not specified, so `rustcpp::dyn_ref` is opaque: it can
+/// only be stored, and given back to Rust. Its size is checked when it is captured.
+///
+/// ```ignore
+/// cpp!{{
+///     struct Callback { rustcpp::dyn_ref handler; };
+/// }}
+/// let handler: &dyn Handler = &my_handler;
+/// cpp!(unsafe [DynRef::new(handler) => handler: DynRef<dyn Handler>] {
+///     Callback cb { handler };
+///     rustcpp::dyn_ref h = cb.handler;
+///     rust!(call_handler [h: DynRef<dyn Handler> as "rustcpp::dyn_ref"] { h.handle() });
+/// });
+/// ```
+#[repr(transparent)]
+pub struct DynRef<'a, T: ?Sized> {
+    ptr: &'a T,
+}
+
+impl<'a, T: ?Sized> DynRef<'a, T> {
+    /// Wrap the reference, so it can be captured as a `rustcpp::dyn_ref`
+    pub fn new(ptr: &'a T) -> Self {
+        DynRef { ptr }
+    }
+
+    /// The wrapped reference, with its original lifetime
+    pub fn get(&self) -> &'a T {
+        self.ptr
+    }
+}
+
+impl<T: ?Sized> Clone for DynRef<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T: ?Sized> Copy for DynRef<'_, T> {}
+
+impl<'a, T: ?Sized> From<&'a T> for DynRef<'a, T> {
+    fn from(ptr: &'a T) -> Self {
+        DynRef { ptr }
+    }
+}
+
+impl<T: ?Sized> Deref for DynRef<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.ptr
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for DynRef<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DynRef").finish_non_exhaustive()
+    }
+}
+
+/// A boxed Rust closure, which is a `rustcpp::function<R(A...)>` in C++, for a
+/// `CppFunction<dyn FnMut(A...) -> R>`.
+///
+/// `rustcpp::function` is callable, and it can be converted to a `std::function` when it
+/// is moved. The closure is dropped by the destructor of the object which owns it: the
+/// `CppFunction`, or the `rustcpp::function` if it is captured with `move`.
+///
+/// The arguments and the return value are passed by value, so their Rust and C++ types must
+/// have the same layout, and the C++ argument types cannot be references. When a capture is
+/// given an explicit `rustcpp::function` type, the number of its arguments and the size and
+/// alignment of each of them and of the return type are checked at compile time.
+///
+/// The copies of a `std::function` share the closure, and its `operator()` is `const`, so the
+/// C++ code can call the closure from another thread: `new` requires the closure to be `Send`.
+/// The C++ code must still not call it from several threads at the same time. A closure which
+/// is not `Send` can be boxed with the unsafe `new_local`.
+///
+/// ```ignore
+/// let mut sum = 0;
+/// let f = CppFunction::<dyn FnMut(i32)>::new(move |x| sum += x);
+/// cpp!(unsafe [move f as "rustcpp::function<void(int)>"] {
+///     std::function<void(int)> callback = std::move(f);
+///     register_callback(callback);
+/// });
+/// ```
+#[cfg(feature = "std")]
+#[repr(C)]
+pub struct CppFunction<F: ?Sized> {
+    data: *mut c_void,
+    call: unsafe extern "C" fn(*mut c_void, *const *mut c_void, *mut c_void),
+    drop: unsafe extern "C" fn(*mut c_void),
+    _marker: PhantomData<std::boxed::Box<F>>,
+}
+
+#[cfg(feature = "std")]
+impl<F: ?Sized> Drop for CppFunction<F> {
+    fn drop(&mut self) {
+        unsafe { (self.drop)(self.data) }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<F: ?Sized> fmt::Debug for CppFunction<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CppFunction").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "std")]
+macro_rules! impl_cpp_function {
+    ($($arg:ident: $t:ident),*) => {
+        impl<R $(, $t)*> CppFunction<dyn FnMut($($t),*) -> R> {
+            /// Box the closure, so it can be passed to C++
+            pub fn new<F: FnMut($($t),*) -> R + Send + 'static>(f: F) -> Self {
+                unsafe { Self::new_local(f) }
+            }
+
+            /// Box a closure which is not `Send`, so it can be passed to C++
+            ///
+            /// # Safety
+            ///
+            /// The C++ code must only call and destroy the closure, and all the copies of
+            /// the `std::function` it is moved into, on the thread which created it.
+            pub unsafe fn new_local<F: FnMut($($t),*) -> R + 'static>(f: F) -> Self {
+                // The arguments are moved out of the array of pointers given by C++
+                #[allow(unused_variables, unused_mut, unused_assignments)]
+                unsafe extern "C" fn call<F: FnMut($($t),*) -> R, R $(, $t)*>(
+                    data: *mut c_void,
+                    args: *const *mut c_void,
+                    ret: *mut c_void,
+                ) {
+                    let mut args = args;
+                    $(
+                        let $arg = (*args as *mut $t).read();
+                        args = args.add(1);
+                    )*
+                    (ret as *mut R).write((*(data as *mut F))($($arg),*));
+                }
+                unsafe extern "C" fn drop<F>(data: *mut c_void) {
+                    core::mem::drop(std::boxed::Box::from_raw(data as *mut F));
+                }
+                CppFunction {
+                    data: std::boxed::Box::into_raw(std::boxed::Box::new(f)) as *mut c_void,
+                    call: call::<F, R $(, $t)*>,
+                    drop: drop::<F>,
+                    _marker: PhantomData,
+                }
+            }
+        }
+
+        impl<R $(, $t)*> crate::CppFunctionSignature for CppFunction<dyn FnMut($($t),*) -> R> {
+            const LAYOUTS: &'static [(usize, usize)] = &[
+                (core::mem::size_of::<R>(), core::mem::align_of::<R>())
+                $(, (core::mem::size_of::<$t>(), core::mem::align_of::<$t>()))*
+            ];
+        }
+    };
+}
+#[cfg(feature = "std")]
+impl_cpp_function!();
+#[cfg(feature = "std")]
+impl_cpp_function!(a1: A1);
+#[cfg(feature = "std")]
+impl_cpp_function!(a1: A1, a2: A2);
+#[cfg(feature = "std")]
+impl_cpp_function!(a1: A1, a2: A2, a3: A3);
+#[cfg(feature = "std")]
+impl_cpp_function!(a1: A1, a2: A2, a3: A3, a4: A4);
+#[cfg(feature = "std")]
+impl_cpp_function!(a1: A1, a2: A2, a3: A3, a4: A4, a5: A5);
+#[cfg(feature = "std")]
+impl_cpp_function!(a1: A1, a2: A2, a3: A3, a4: A4, a5: A5, a6: A6);
+
+/// An iterator over a C++ range, returned by `cpp_iter!`.
+///
+/// It borrows the container `C`, and holds the C++ iterators in `S`, an opaque buffer of the
+/// size of the C++ iterators, which are destroyed when it is dropped. It is `Send` and `Sync`
+/// when the container is `Sync`, like a shared reference to it.
+pub struct CppIter<'a, C, S, T> {
+    state: S,
+    next: unsafe fn(&mut S) -> Option<T>,
+    drop: unsafe fn(&mut S),
+    _marker: PhantomData<&'a C>,
+}
+
+impl<'a, C, S, T> CppIter<'a, C, S, T> {
+    /// Used by `cpp_iter!`.
+    ///
+    /// # Safety
+    ///
+    /// `state` must hold the C++ iterators over `container`, and `next` and `drop` must be
+    /// the functions which use them.
+    #[doc(hidden)]
+    pub unsafe fn __new(
+        _container: &'a C,
+        state: S,
+        next: unsafe fn(&mut S) -> Option<T>,
+        drop: unsafe fn(&mut S),
+    ) -> Self {
+        CppIter {
+            state,
+            next,
+            drop,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<C, S, T> Iterator for CppIter<'_, C, S, T> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        unsafe { (self.next)(&mut self.state) }
+    }
+}
+
+impl<C, S, T> Drop for CppIter<'_, C, S, T> {
+    fn drop(&mut self) {
+        unsafe { (self.drop)(&mut self.state) }
+    }
+}
+
+impl<C, S, T> fmt::Debug for CppIter<'_, C, S, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CppIter").finish_non_exhaustive()
+    }
+}
@@ -52,7 +52,7 @@
 //!     let name = std::ffi::CString::new("World").unwrap();
 //!     let name_ptr = name.as_ptr();
 //!     let r = unsafe {
-//!         cpp!([name_ptr as "const char *"] -> u32 as "int32_t" {
+//!         cpp!([name_ptr as "const char *"] -> i32 as "int32_t" {
 //!             std::cout << "Hello, " << name_ptr << std::endl;
 //!             return 42;
 //!         })
@@ -61,6 +61,12 @@
 //! }
 //! ```
 //!
+//! # `no_std`
+//!
+//! This crate is `no_std`. The code generated by the macros uses `std` only if the
+//! `std` feature is enabled (which is the default). Use `default-features = false` in
+//! the dependency on `cpp` to only use `core`.
+//!
 //! # Build script
 //!
 //! Use the `cpp_build` crates from your `build.rs` script.
@@ -68,6 +74,14 @@
 //! You can simply use the `cpp_build::build` function, or the `cpp_build::Config`
 //! struct if you want more option.
 //!
+//! The build script only looks at the crate whose root is given. If `cpp!` is used
+//! from several targets of the package (e.g. a binary, or integration tests in the
+//! `tests/` directory), they should all be given to `cpp_build::Config::build_roots`.
+//! When the package has a library, cargo only links the C++ code with it, so the other
+//! targets must link the library crate (e.g. with `extern crate my_crate;`).
+//! Files which should not be looked at can be skipped with `cpp_build::Config::exclude`
+//! or `cpp_build::Config::only`.
+//!
 //! Behind the scene, it uses the `cc` crate.
 //!
 //! ## Using external libraries
@@ -104,6 +118,20 @@
 //! in its simplest form: straight-forward `feature = "..."` without any
 //! additional conditions, `cfg!` macros are also not supported at the moment.
 //!
+//! The `cpp!` and `cpp_class!` macros can be generated by `macro_rules!` macros
+//! defined in the same crate. The build script expands their invocations textually,
+//! by substituting the text matched by each metavariable. This only works for
+//! simple macros: the fragments are matched without really parsing them, and
+//! macros with the same name in different modules are not distinguished. An
+//! invocation which cannot be expanded is reported as a build script warning, and
+//! only the rust-cpp macros it generates are then missing from the library: their
+//! error names the invocations which could not be expanded.
+//!
+//! Files included with `include!` are also parsed, as long as their path is a string
+//! literal, or is computed with `concat!` and `env!` (for example
+//! `include!(concat!(env!("OUT_DIR"), "/generated.rs"))`). A file generated by the
+//! build script must be written before `cpp_build::build` is called.
+//!
 //! Since the C++ code is included within a rust file, the C++ code must obey both
 //! the Rust and the C++ lexing rules. For example, Rust supports nested block comments
 //! (`/* ... /* ... */ ... */`) while C++ does not, so nested comments not be used in the
@@ -113,6 +141,7 @@
 //! Be careful to properly use `#if` / `#else` / `#endif`, and not have unbalanced delimiters.
 
 #![no_std]
+#![allow(clippy::needless_doctest_main)]
 
 #[macro_use]
 #[allow(unused_imports)]
@@ -120,6 +149,21 @@ extern crate cpp_macros;
 #[doc(hidden)]
 pub use cpp_macros::*;
 
+#[cfg(feature = "std")]
+extern crate std;
+
+mod bridge;
+#[cfg(feature = "std")]
+pub use bridge::CppFunction;
+pub use bridge::{CppIter, CppString, DynRef, Slice, SliceMut, StrView};
+
+/// Items used by the code generated by the macros
+#[doc(hidden)]
+pub mod __private {
+    #[cfg(feature = "std")]
+    pub use std::boxed::Box;
+}
+
 /// Internal macro which is used to locate the `rust!` invocations in the
 /// C++ code embedded in `cpp!` invocation, to translate them into `extern`
 /// functions
@@ -140,11 +184,21 @@ macro_rules! __cpp_internal {
         { $crate::__cpp_internal!{ @find_rust_macro [$($a)*] $($rest)* } };
     (@find_rust_macro [$($a:tt)*]) => {};
 
+    // The callbacks of the snippets are exported with a symbol name specific to the crate
+    (@expand_rust_macro [@export] $i:ident $($rest:tt)*) => {
+        const _: () = {
+            #[allow(unused)]
+            #[derive($crate::__cpp_internal_rust_callback)]
+            enum RustCallbackInput {
+                Input = (stringify!($i), 0).1
+            }
+            __cpp_rust_callback_impl!{ $i $($rest)* }
+        };
+    };
     (@expand_rust_macro [$($a:tt)*] $i:ident [$($an:ident : $at:ty as $ac:tt),*] {$($body:tt)*}) => {
         #[allow(non_snake_case)]
         #[allow(unused_unsafe)]
-        #[cfg_attr(feature = "cargo-clippy", allow(clippy::forget_copy))]
-        #[cfg_attr(feature = "cargo-clippy", allow(clippy::forget_ref))]
+        #[allow(unknown_lints, forgetting_copy_types, forgetting_references)]
         #[doc(hidden)]
         $($a)* unsafe extern "C" fn $i($($an : *const $at),*) {
             $(let $an : $at = unsafe { $an.read() };)*
@@ -156,8 +210,7 @@ macro_rules! __cpp_internal {
     (@expand_rust_macro [$($a:tt)*] $i:ident [$($an:ident : $at:ty as $ac:tt),*] -> $rt:ty as $rc:tt {$($body:tt)*}) => {
         #[allow(non_snake_case)]
         #[allow(unused_unsafe)]
-        #[cfg_attr(feature = "cargo-clippy", allow(clippy::forget_copy))]
-        #[cfg_attr(feature = "cargo-clippy", allow(clippy::forget_ref))]
+        #[allow(unknown_lints, forgetting_copy_types, forgetting_references)]
         #[doc(hidden)]
         $($a)* unsafe extern "C" fn $i($($an : *const $at, )* rt : *mut $rt) -> *mut $rt {
 
@@ -177,6 +230,93 @@ macro_rules! __cpp_internal {
     };
 }
 
+// Defines the items of the first block on the platforms which support static constructors,
+// and the items of the second block on the other platforms. The fallback of the other
+// platforms can be tested with `RUSTFLAGS="--cfg rust_cpp_lazy_constructors"`.
+macro_rules! cfg_static_constructors {
+    ({ $($yes:item)* } else { $($no:item)* }) => {
+        cfg_static_constructors! {
+            @cfg all(not(rust_cpp_lazy_constructors), any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "freebsd",
+                target_os = "dragonfly",
+                target_os = "netbsd",
+                target_os = "openbsd",
+                target_os = "illumos",
+                target_os = "solaris",
+                target_os = "fuchsia",
+                target_os = "haiku",
+                target_vendor = "apple",
+                windows
+            )),
+            { $($yes)* } else { $($no)* }
+        }
+    };
+    (@cfg $cfg:meta, { $($yes:item)* } else { $($no:item)* }) => {
+        $(#[cfg($cfg)] $yes)*
+        $(#[cfg(not($cfg))] $no)*
+    };
+}
+
+cfg_static_constructors! {
+    {
+        /// Internal macro which runs the given function before `main` (or when the library is
+        /// loaded). This is used to register the `rust!` callbacks of the closures.
+        #[doc(hidden)]
+        #[macro_export]
+        macro_rules! __cpp_static_constructor {
+            ($f:ident) => {
+                #[used]
+                #[cfg_attr(target_vendor = "apple", link_section = "__DATA,__mod_init_func")]
+                #[cfg_attr(windows, link_section = ".CRT$XCU")]
+                #[cfg_attr(
+                    not(any(target_vendor = "apple", windows)),
+                    link_section = ".init_array"
+                )]
+                static __CPP_STATIC_CONSTRUCTOR: unsafe extern "C" fn() = $f;
+            };
+        }
+        #[doc(hidden)]
+        #[macro_export]
+        macro_rules! __cpp_lazy_constructor {
+            ($f:ident) => {};
+        }
+    } else {
+        // Platforms without static constructors call the function the first time the closure
+        // runs
+        #[doc(hidden)]
+        #[macro_export]
+        macro_rules! __cpp_static_constructor {
+            ($f:ident) => {};
+        }
+        #[cfg(feature = "std")]
+        #[doc(hidden)]
+        #[macro_export]
+        macro_rules! __cpp_lazy_constructor {
+            ($f:ident) => {
+                static INIT: ::std::sync::Once = ::std::sync::Once::new();
+                INIT.call_once(|| unsafe { $f() });
+            };
+        }
+        #[cfg(not(feature = "std"))]
+        #[doc(hidden)]
+        #[macro_export]
+        macro_rules! __cpp_lazy_constructor {
+            ($f:ident) => {
+                // Concurrent calls may all run the function, which is fine since they store the
+                // same values.
+                static INIT: ::core::sync::atomic::AtomicBool =
+                    ::core::sync::atomic::AtomicBool::new(false);
+                if !INIT.load(::core::sync::atomic::Ordering::Acquire) {
+                    unsafe { $f() };
+                    INIT.store(true, ::core::sync::atomic::Ordering::Release);
+                }
+            };
+        }
+    }
+}
+
 /// This macro is used to embed arbitrary C++ code.
 ///
 /// There are two variants of the `cpp!` macro. The first variant is used for
@@ -216,6 +356,68 @@ macro_rules! __cpp_internal {
 /// });
 /// ```
 ///
+/// The C++ type of a capture can be omitted, or written `as auto`, if the rust type of
+/// the capture is annotated. The build script then infers the C++ type from the rust
+/// type: the primitive types (e.g. `i32` is `int32_t`, `f64` is `double`), the
+/// `std::os::raw` types, pointers to those, and the types declared with `cpp_class!` in
+/// the same crate are known. The types are found by the last segment of their path, so the
+/// C++ type of a name declared by several `cpp_class!` must be explicit. Other types need
+/// an explicit C++ type. Like the return type, an annotated type is checked against the
+/// C++ type by a constant item, so it cannot use the generic parameters of the enclosing
+/// function.
+///
+/// ```ignore
+/// let x: i32 = cpp!(unsafe [y: i32, mut z: i32 as auto] -> i32 as "int32_t" {
+///     z++;
+///     return y + z;
+/// });
+/// ```
+///
+/// Instead of a variable, a capture can be any Rust expression, followed by `=>` and the
+/// name of the C++ parameter. The expression is evaluated before the C++ code is run,
+/// and the C++ code cannot modify it (a `mut` parameter modifies a temporary).
+///
+/// ```ignore
+/// let sum: u32 = cpp!(unsafe [buf.as_ptr() => data as "const uint8_t*",
+///                             buf.len() => len as "size_t"] -> u32 as "uint32_t" {
+///     return std::accumulate(data, data + len, 0u);
+/// });
+/// ```
+///
+/// Captures are passed to C++ as `const T&`, or `T&` for `mut` captures. A `move`
+/// capture transfers the ownership of the value to the C++ code, which receives a `T&&`
+/// and destroys the object when it returns. The Rust value is moved and never dropped.
+/// This allows to pass move-only types such as `std::unique_ptr` by value.
+///
+/// ```ignore
+/// cpp!(unsafe [move widget as "std::unique_ptr<Widget>"] {
+///     registry.push_back(std::move(widget));
+/// });
+/// ```
+///
+/// Strings and slices cannot be captured directly. They can be passed with the
+/// [`StrView`], [`Slice`] and [`SliceMut`] types, and a `std::string` can be returned
+/// as a [`CppString`]. Their C++ types are inferred when the capture is annotated.
+///
+/// ```ignore
+/// let upper = cpp!(unsafe [StrView::from(name) => name: StrView] -> CppString as "rustcpp::string" {
+///     std::string s = name;
+///     std::transform(s.begin(), s.end(), s.begin(), ::toupper);
+///     return s;
+/// });
+/// ```
+///
+/// With the `std` feature, a Rust closure can be boxed in a [`CppFunction`], which is a
+/// `rustcpp::function` in C++. It can be called, or moved into a `std::function`.
+///
+/// ```ignore
+/// let callback = CppFunction::<dyn FnMut(i32)>::new(|x| println!("{}", x));
+/// cpp!(unsafe [move callback: CppFunction<dyn FnMut(i32)>] {
+///     std::function<void(int32_t)> f = std::move(callback);
+///     f(42);
+/// });
+/// ```
+///
 /// ## rust! pseudo-macro
 ///
 /// The `cpp!` macro can contain, in the C++ code, a `rust!` sub-macro, which allows
@@ -228,12 +430,11 @@ macro_rules! __cpp_internal {
 /// }
 ///
 /// cpp!{{
-///    struct TraitPtr { void *a,*b; };
 ///    class MyClassImpl : public MyClass {
 ///      public:
-///        TraitPtr m_trait;
+///        rustcpp::dyn_ref m_trait;
 ///        int computeValue(int x) const override {
-///            return rust!(MCI_computeValue [m_trait : &MyTrait as "TraitPtr", x : i32 as "int"]
+///            return rust!(MCI_computeValue [m_trait : DynRef<dyn MyTrait> as "rustcpp::dyn_ref", x : i32 as "int"]
 ///                -> i32 as "int" {
 ///                m_trait.compute_value(x)
 ///            });
@@ -247,11 +448,17 @@ macro_rules! __cpp_internal {
 /// rust!($uniq_ident:ident [$($arg_name:ident : $arg_rust_type:ty as $arg_c_type:tt),*]
 ///      $(-> $ret_rust_type:ty as $rust_c_type:tt)* {$($body:tt)*})
 /// ```
-/// `uniq_ident` is a unique identifier which will be used to name the `extern` function
+/// `uniq_ident` is a unique identifier which will be used to name the `extern` function.
+/// Within `cpp!{{ }}` snippets, the symbol name is prefixed with the package name, so
+/// it only needs to be unique within the package, and the function is not visible from
+/// Rust code.
+///
+/// To implement the virtual functions of a C++ class with a Rust trait, [`cpp_impl!`]
+/// generates the subclass and the `rust!` callbacks.
 #[macro_export]
 macro_rules! cpp {
     // raw text inclusion
-    ({$($body:tt)*}) => { $crate::__cpp_internal!{ @find_rust_macro [#[no_mangle] pub] $($body)*} };
+    ({$($body:tt)*}) => { $crate::__cpp_internal!{ @find_rust_macro [@export] $($body)*} };
 
     // inline closure
     ([$($captures:tt)*] $($rest:tt)*) => {
@@ -270,6 +477,59 @@ macro_rules! cpp {
     (unsafe $($tail:tt)*) => { unsafe { cpp!($($tail)*) } };
 }
 
+/// The kind of a rust type, compared with the C++ type of the captures and return
+/// values of the `cpp!` closures. See `CppTypeKind`.
+#[doc(hidden)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TypeKind {
+    SignedInteger,
+    UnsignedInteger,
+    FloatingPoint,
+    Pointer,
+}
+
+/// Implemented for the rust primitive types and pointers, in order to check at compile
+/// time that they are not passed to or returned from a `cpp!` closure as a C++ type of a
+/// different kind (e.g. an `f32` as `"int"`, or an `i32` as `"unsigned"`).
+///
+/// Other types are not checked: to pass a value as an opaque type of another kind (e.g. an
+/// integer handle as a C++ pointer), wrap it in a `#[repr(transparent)]` struct.
+#[doc(hidden)]
+pub trait CppTypeKind {
+    const KIND: TypeKind;
+}
+
+macro_rules! impl_cpp_type_kind {
+    ($kind:ident: $($t:ty),*) => {
+        $(impl CppTypeKind for $t {
+            const KIND: TypeKind = TypeKind::$kind;
+        })*
+    };
+}
+impl_cpp_type_kind!(SignedInteger: i8, i16, i32, i64, i128, isize);
+impl_cpp_type_kind!(UnsignedInteger: u8, u16, u32, u64, u128, usize, bool, char);
+impl_cpp_type_kind!(FloatingPoint: f32, f64);
+impl<T: ?Sized> CppTypeKind for *const T {
+    const KIND: TypeKind = TypeKind::Pointer;
+}
+impl<T: ?Sized> CppTypeKind for *mut T {
+    const KIND: TypeKind = TypeKind::Pointer;
+}
+impl<T: ?Sized> CppTypeKind for &T {
+    const KIND: TypeKind = TypeKind::Pointer;
+}
+impl<T: ?Sized> CppTypeKind for &mut T {
+    const KIND: TypeKind = TypeKind::Pointer;
+}
+
+/// Implemented by `CppFunction`, in order to check at compile time that its signature
+/// matches the C++ `rustcpp::function` of a capture: the size and alignment of the return
+/// type, followed by those of the arguments.
+#[doc(hidden)]
+pub trait CppFunctionSignature {
+    const LAYOUTS: &'static [(usize, usize)];
+}
+
 #[doc(hidden)]
 pub trait CppTrait {
     type BaseType;
@@ -317,6 +577,22 @@ pub trait CppTrait {
 ///   be called twice. Note that it will never return `None`.
 /// * The trait `Ord` can also be specified when the semantics of the `operator<` corresponds
 ///   to a total order
+/// * The arithmetic and bitwise operators `Add`, `Sub`, `Mul`, `Div`, `Rem`, `BitAnd`,
+///   `BitOr`, `BitXor`, `Shl`, `Shr` and `Neg` call the corresponding C++ operator. They are
+///   implemented for the class and for references to it, with the class as the other
+///   operand and as the output. The compound assignments (`AddAssign`, ...) call the
+///   C++ `operator+=`, ...
+/// * The traits `Index` and `IndexMut` call the C++ `operator[]`, which must return a
+///   reference. They need the Rust types of the index and of the elements, which are given
+///   with the `cpp_index` attribute. The size and alignment of the elements are checked.
+///
+/// ```ignore
+/// cpp_class!(#[derive(Add, Sub, Neg, AddAssign, Index, IndexMut)] #[cpp_index(usize => f64)]
+///     unsafe struct Vec3 as "Vec3");
+///
+/// let c = &a + &b;
+/// assert_eq!(c[0], a[0] + b[0]);
+/// ```
 ///
 /// ## Safety Warning
 ///
@@ -344,16 +620,428 @@ pub trait CppTrait {
 /// most std:: classes.
 /// But `std::unique_ptr<T>` and `std::shared_ptr<T>` works.
 ///
+/// ## Standard containers and smart pointers
+///
+/// A `std::vector`, `std::unique_ptr` or `std::shared_ptr` can be declared with the Rust
+/// type of its elements, after the C++ type. The size and alignment of the elements are
+/// checked, and an API is generated:
+///
+/// ```ignore
+/// cpp_class!(pub unsafe struct VecInt as "std::vector<int>" vector_of i32);
+/// cpp_class!(pub unsafe struct WidgetPtr as "std::unique_ptr<Widget>" unique_ptr_of Widget);
+/// cpp_class!(pub unsafe struct SharedDouble as "std::shared_ptr<double>" shared_ptr_of f64);
+/// ```
+///
+///  * `vector_of T` generates the `len`, `is_empty`, `push`, `as_slice` and `as_mut_slice`
+///    methods, and implements `Index<usize>`, `IndexMut<usize>` and `IntoIterator` for
+///    references. (`std::vector<bool>` is not supported, as it does not store `bool`s.)
+///  * `unique_ptr_of T` generates the `new`, `get`, `get_mut` and `is_null` methods, and
+///    implements `Deref` and `DerefMut`, which panic if the pointer is null.
+///  * `shared_ptr_of T` is the same as `unique_ptr_of T`, without mutable access.
+///
+/// Note that with MSVC in debug mode, `std::vector` is not relocatable.
+///
+/// ## Inheritance
+///
+/// The `cpp_class!` of the base class can be given after the C++ type. The derived class
+/// implements `Deref<Target = Base>`, which converts the pointer with a `static_cast`, so
+/// the methods of the base class can be called on it.
+///
+/// ```ignore
+/// cpp_class!(pub unsafe struct Shape as "Shape");
+/// cpp_class!(pub unsafe struct Circle as "Circle" : Shape);
+///
+/// let area = circle.area(); // Shape::area
+/// if let Some(circle) = Circle::downcast(shape) { ... }
+/// ```
+///
+/// `DerefMut` is not implemented: assigning a whole base object through the reference would
+/// skip the C++ assignment operator, and overwrite the parts of the derived object which can
+/// share the storage of the base (its virtual table pointer, the fields placed in the tail
+/// padding of the base, or the object at the same address as an empty base). Instead,
+/// `unsafe fn as_base_mut` gives a mutable reference to the base class, which must only be
+/// used to call its methods. If the base class is polymorphic, `downcast` and `downcast_mut`
+/// convert a reference to the base class with a `dynamic_cast`.
+///
+/// ## Fields
+///
+/// The leading fields of the C++ class can be declared, in order, with the `cpp_fields`
+/// attribute. Their offset and size are checked to be the same as in a `#[repr(C)]` Rust
+/// struct with these fields, so that fields which are reordered or have a different type
+/// are reported at compile time. Accessors are generated for the fields which are not
+/// private.
+///
+/// ```ignore
+/// cpp_class!(#[cpp_fields(pub x: i32, pub y: i32, id: u64)] unsafe struct Point as "Point");
+///
+/// let mut p = Point::default();
+/// *p.x_mut() = 5;
+/// assert_eq!(*p.x(), 5);
+/// ```
+///
+/// The C++ class must be standard-layout for `offsetof` to be well defined. Rust structs
+/// passed to C++ can be checked in the same way with [`cpp_export_struct!`].
+///
 #[macro_export]
 macro_rules! cpp_class {
     ($(#[$($attrs:tt)*])* unsafe struct $name:ident as $type:expr) => {
-        $crate::__cpp_class_internal!{@parse [ $(#[$($attrs)*])* ] [] [unsafe struct $name as $type] }
+        $crate::__cpp_class_internal!{@parse [ $(#[$($attrs)*])* ] [] [unsafe struct $name as $type] [] }
     };
     ($(#[$($attrs:tt)*])* pub unsafe struct $name:ident as $type:expr) => {
-        $crate::__cpp_class_internal!{@parse [ $(#[$($attrs)*])* ] [pub] [unsafe struct $name as $type] }
+        $crate::__cpp_class_internal!{@parse [ $(#[$($attrs)*])* ] [pub] [unsafe struct $name as $type] [] }
     };
     ($(#[$($attrs:tt)*])* pub($($pub:tt)*) unsafe struct $name:ident as $type:expr) => {
-        $crate::__cpp_class_internal!{@parse [ $(#[$($attrs)*])* ] [pub($($pub)*)] [unsafe struct $name as $type] }
+        $crate::__cpp_class_internal!{@parse [ $(#[$($attrs)*])* ] [pub($($pub)*)] [unsafe struct $name as $type] [] }
+    };
+    ($(#[$($attrs:tt)*])* unsafe struct $name:ident as $type:literal $kind:ident $element:ty) => {
+        $crate::__cpp_class_internal!{@parse [ $(#[$($attrs)*])* ] [] [unsafe struct $name as $type] [$kind $element] }
+    };
+    ($(#[$($attrs:tt)*])* pub unsafe struct $name:ident as $type:literal $kind:ident $element:ty) => {
+        $crate::__cpp_class_internal!{@parse [ $(#[$($attrs)*])* ] [pub] [unsafe struct $name as $type] [$kind $element] }
+    };
+    ($(#[$($attrs:tt)*])* pub($($pub:tt)*) unsafe struct $name:ident as $type:literal $kind:ident $element:ty) => {
+        $crate::__cpp_class_internal!{@parse [ $(#[$($attrs)*])* ] [pub($($pub)*)] [unsafe struct $name as $type] [$kind $element] }
+    };
+    ($(#[$($attrs:tt)*])* unsafe struct $name:ident as $type:literal : $base:ty) => {
+        $crate::__cpp_class_internal!{@parse [ $(#[$($attrs)*])* ] [] [unsafe struct $name as $type] [: $base] }
+    };
+    ($(#[$($attrs:tt)*])* pub unsafe struct $name:ident as $type:literal : $base:ty) => {
+        $crate::__cpp_class_internal!{@parse [ $(#[$($attrs)*])* ] [pub] [unsafe struct $name as $type] [: $base] }
+    };
+    ($(#[$($attrs:tt)*])* pub($($pub:tt)*) unsafe struct $name:ident as $type:literal : $base:ty) => {
+        $crate::__cpp_class_internal!{@parse [ $(#[$($attrs)*])* ] [pub($($pub)*)] [unsafe struct $name as $type] [: $base] }
+    };
+}
+
+/// This macro declares a name for a C++ type, which the captures of the `cpp!`
+/// closures of the crate can use instead of repeating the C++ type.
+///
+/// ```ignore
+/// cpp_type!(WidgetPtr = "const ns::Widget*");
+///
+/// fn widget_size(widget: *const Widget) -> usize {
+///     unsafe { cpp!([widget as WidgetPtr] -> usize as "size_t" { return widget->size(); }) }
+/// }
+/// ```
+///
+/// The alias is collected by the build script, so it can be used anywhere in the
+/// crate, and it does not define anything on the Rust side. An alias can only be
+/// declared again with the same C++ type, otherwise the build script fails.
+#[macro_export]
+macro_rules! cpp_type {
+    ($name:ident = $type:literal) => {};
+}
+
+/// This macro defines a Rust constant with the value of a C++ constant expression, which
+/// is evaluated by the build script.
+///
+/// ```ignore
+/// cpp_const!(pub const FOO_MAX: u32 = "FOO_MAX");
+/// cpp_const!(const WIDGET_SIZE: usize = "sizeof(Widget)");
+/// cpp_const!(const BAR: i32 = "static_cast<int>(Enum::Bar)");
+/// ```
+///
+/// The C++ expression must be an integer, a `bool` or an enumeration. It initializes a
+/// `constexpr` variable, so the build script fails if it is not a constant expression. Its
+/// value is checked at compile time to fit in the Rust type.
+#[macro_export]
+macro_rules! cpp_const {
+    ($(#[$($attrs:tt)*])* const $name:ident : $ty:ty = $cpp:literal $(;)?) => {
+        $crate::__cpp_const_internal!{ [ $(#[$($attrs)*])* ] [] $name $ty, $cpp }
+    };
+    ($(#[$($attrs:tt)*])* pub const $name:ident : $ty:ty = $cpp:literal $(;)?) => {
+        $crate::__cpp_const_internal!{ [ $(#[$($attrs)*])* ] [pub] $name $ty, $cpp }
+    };
+    ($(#[$($attrs:tt)*])* pub($($pub:tt)*) const $name:ident : $ty:ty = $cpp:literal $(;)?) => {
+        $crate::__cpp_const_internal!{ [ $(#[$($attrs)*])* ] [pub($($pub)*)] $name $ty, $cpp }
+    };
+}
+
+/// Implementation details for cpp_const!
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __cpp_const_internal {
+    ([$($attrs:tt)*] [$($vis:tt)*] $name:ident $ty:ty, $cpp:literal) => {
+        $($attrs)*
+        $($vis)* const $name: $ty = {
+            #[allow(unused)]
+            #[derive($crate::__cpp_internal_const)]
+            enum CppConstInput {
+                Input = (stringify!($($vis)* const $name: $ty = $cpp), 0).1
+            }
+            __cpp_const_impl!()
+        };
+    };
+}
+
+/// This macro defines a Rust enum with the values of the enumerators of a C++ enum.
+///
+/// ```ignore
+/// cpp_enum!(#[derive(Clone, Copy, Debug, PartialEq)] pub enum Color as "ns::Color" {
+///     Red,
+///     Green,
+///     Blue,
+/// });
+/// cpp_enum!(pub enum Flags: u8 as "Flags" { A, B });
+///
+/// assert_eq!(Color::try_from(Color::Green as i32), Ok(Color::Green));
+/// ```
+///
+/// The enum is `#[repr(i32)]`, or uses the given integer type, which is checked against the
+/// underlying type of the C++ enum. `TryFrom` is implemented for this integer type, and
+/// `From<Color>` for the integer.
+///
+/// The build script fails if a C++ enumerator is not listed (with GCC, clang and MSVC), as
+/// its value would be an invalid Rust enum. An enumerator which has the same value as another
+/// one can't be listed.
+///
+/// **Warning:** a C++ enum can hold values which are not enumerators, e.g. combinations of
+/// flags. Capturing such a value with the Rust enum type is undefined behavior, so the values
+/// which are not known to be enumerators must be passed as integers and converted with
+/// `TryFrom`.
+#[macro_export]
+macro_rules! cpp_enum {
+    ($(#[$($attrs:tt)*])* enum $name:ident $(: $ty:ident)? as $cpp:literal { $($variant:ident),* $(,)? }) => {
+        $crate::__cpp_enum_internal!{ [ $(#[$($attrs)*])* ] [] $name [$($ty)?]
+            [enum $name $(: $ty)? as $cpp { $($variant),* }] [$($variant)*] }
+    };
+    ($(#[$($attrs:tt)*])* pub enum $name:ident $(: $ty:ident)? as $cpp:literal { $($variant:ident),* $(,)? }) => {
+        $crate::__cpp_enum_internal!{ [ $(#[$($attrs)*])* ] [pub] $name [$($ty)?]
+            [pub enum $name $(: $ty)? as $cpp { $($variant),* }] [$($variant)*] }
+    };
+    ($(#[$($attrs:tt)*])* pub($($pub:tt)*) enum $name:ident $(: $ty:ident)? as $cpp:literal { $($variant:ident),* $(,)? }) => {
+        $crate::__cpp_enum_internal!{ [ $(#[$($attrs)*])* ] [pub($($pub)*)] $name [$($ty)?]
+            [pub($($pub)*) enum $name $(: $ty)? as $cpp { $($variant),* }] [$($variant)*] }
+    };
+}
+
+/// Implementation details for cpp_enum!
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __cpp_enum_internal {
+    ([$($attrs:tt)*] [$($vis:tt)*] $name:ident [] [$($input:tt)*] [$($variant:ident)*]) => {
+        $crate::__cpp_enum_internal!{ [$($attrs)*] [$($vis)*] $name [i32] [$($input)*] [$($variant)*] }
+    };
+    ([$($attrs:tt)*] [$($vis:tt)*] $name:ident [$repr:ident] $input:tt [$($variant:ident)*]) => {
+        $($attrs)*
+        #[repr($repr)]
+        $($vis)* enum $name {
+            $($variant = {
+                #[allow(unused)]
+                #[derive($crate::__cpp_internal_enum)]
+                enum CppEnumInput {
+                    Input = (stringify!($variant in $input), 0).1
+                }
+                __cpp_enum_impl!()
+            },)*
+        }
+
+        const _: () = {
+            #[allow(unused)]
+            #[derive($crate::__cpp_internal_enum)]
+            enum CppEnumInput {
+                Input = (stringify!($input), 0).1
+            }
+            __cpp_enum_impl!();
+        };
+
+        impl ::core::convert::TryFrom<$repr> for $name {
+            type Error = $repr;
+            fn try_from(value: $repr) -> ::core::result::Result<Self, $repr> {
+                $(if value == $name::$variant as $repr {
+                    return ::core::result::Result::Ok($name::$variant);
+                })*
+                ::core::result::Result::Err(value)
+            }
+        }
+
+        impl ::core::convert::From<$name> for $repr {
+            fn from(value: $name) -> $repr {
+                value as $repr
+            }
+        }
+    };
+}
+
+/// This macro defines a `#[repr(C)]` Rust struct, and the build script generates the
+/// definition of the equivalent C++ struct.
+///
+/// ```ignore
+/// cpp_export_struct! {
+///     #[derive(Clone, Copy, Debug)]
+///     pub struct Point as "geo::Point" {
+///         pub x: i32,
+///         pub y: i32,
+///         pub name: *const c_char as "const char*",
+///     }
+/// }
+///
+/// let p = Point { x: 1, y: 2, name: core::ptr::null() };
+/// let sum = unsafe { cpp!([p as "geo::Point"] -> i32 as "int32_t" { return p.x + p.y; }) };
+/// ```
+///
+/// The C++ structs are defined before the `cpp!{{ }}` snippets, in the namespace given
+/// by the qualified name, which may start with `::`. The C++ type of the fields is inferred from their Rust type like
+/// for the captures of `cpp!`, and can also refer to the previous exported structs. Other
+/// types must be given with `as "..."` or a `cpp_type!` alias.
+///
+/// The size and alignment of the struct, and the offset and size of each field, are
+/// checked to be the same in C++ and in Rust.
+#[macro_export]
+macro_rules! cpp_export_struct {
+    ($(#[$($attrs:tt)*])* struct $name:ident as $cpp:literal {
+        $($(#[$($fattrs:tt)*])* $fvis:vis $field:ident : $ty:ty $(as $fcpp:tt)?),* $(,)?
+    }) => {
+        $crate::__cpp_export_struct_internal!{ [ $(#[$($attrs)*])* ] [] $name
+            { $($(#[$($fattrs)*])* $fvis $field: $ty,)* }
+            [struct $name as $cpp { $($fvis $field: $ty $(as $fcpp)?),* }] }
+    };
+    ($(#[$($attrs:tt)*])* pub struct $name:ident as $cpp:literal {
+        $($(#[$($fattrs:tt)*])* $fvis:vis $field:ident : $ty:ty $(as $fcpp:tt)?),* $(,)?
+    }) => {
+        $crate::__cpp_export_struct_internal!{ [ $(#[$($attrs)*])* ] [pub] $name
+            { $($(#[$($fattrs)*])* $fvis $field: $ty,)* }
+            [pub struct $name as $cpp { $($fvis $field: $ty $(as $fcpp)?),* }] }
+    };
+    ($(#[$($attrs:tt)*])* pub($($pub:tt)*) struct $name:ident as $cpp:literal {
+        $($(#[$($fattrs:tt)*])* $fvis:vis $field:ident : $ty:ty $(as $fcpp:tt)?),* $(,)?
+    }) => {
+        $crate::__cpp_export_struct_internal!{ [ $(#[$($attrs)*])* ] [pub($($pub)*)] $name
+            { $($(#[$($fattrs)*])* $fvis $field: $ty,)* }
+            [pub($($pub)*) struct $name as $cpp { $($fvis $field: $ty $(as $fcpp)?),* }] }
+    };
+}
+
+/// Implementation details for cpp_export_struct!
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __cpp_export_struct_internal {
+    ([$($attrs:tt)*] [$($vis:tt)*] $name:ident $fields:tt $input:tt) => {
+        $($attrs)*
+        #[repr(C)]
+        $($vis)* struct $name $fields
+
+        const _: () = {
+            #[allow(unused)]
+            #[derive($crate::__cpp_internal_export_struct)]
+            enum CppStructInput {
+                Input = (stringify!($input), 0).1
+            }
+        };
+    };
+}
+
+/// This macro iterates over a C++ range: a container, or any object for which
+/// `std::begin` and `std::end` are defined.
+///
+/// ```ignore
+/// cpp_class!(unsafe struct VecIntA as "std::vector<std::pair<int, A>>");
+///
+/// let pairs: VecIntA = ...;
+/// for (key, value) in cpp_iter!(unsafe pairs as "const std::vector<std::pair<int, A>>&" => (i32, &A)) {
+///     println!("{}: {}", key, value.multiply());
+/// }
+/// let keys: Vec<i32> = unsafe {
+///     cpp_iter!((self.pairs) as "const std::vector<std::pair<int, A>>&" => (i32, A))
+/// }
+/// .map(|(k, _)| k)
+/// .collect();
+/// ```
+///
+/// Like `cpp!`, this macro is unsafe: it must be called from an unsafe block, or start
+/// with `unsafe`. The caller must make sure that the container is of the given C++ type,
+/// and that the items are valid values of the given Rust type.
+///
+/// The container is a variable, or an expression in parentheses, which is borrowed by the
+/// returned [`CppIter`]. Its size is checked against the C++ type, which can be a reference.
+/// As the borrow is shared, the container is always iterated as `const`, with the `const`
+/// overloads of `begin` and `end`.
+///
+/// The items are converted to the given Rust type. A tuple is made of the elements given by
+/// `std::get` (e.g. the key and the value of a `std::pair`). A reference borrows the element
+/// from the container, which must give an lvalue, and other types are copied. The size and
+/// alignment of each element are checked.
+///
+/// The C++ iterators are stored in the `CppIter`, so they must be relocatable like the
+/// types of [`cpp_class!`]. (Note that with MSVC in debug mode, the iterators of the
+/// standard containers are not.) The container is only borrowed, so it can be any C++ type,
+/// e.g. a `std::map` behind a `std::unique_ptr`.
+#[macro_export]
+#[rustfmt::skip]
+macro_rules! cpp_iter {
+    ($container:tt as $cpp:literal => $item:ty) => {{
+        #[allow(unused)]
+        #[derive($crate::__cpp_internal_iter)]
+        enum CppIterInput {
+            Input = (stringify!($container as $cpp => $item), 0).1,
+        }
+        __cpp_iter_impl!($container)
+    }};
+
+    // wrap unsafe
+    (unsafe $($tail:tt)*) => { unsafe { cpp_iter!($($tail)*) } };
+}
+
+/// This macro generates a C++ subclass of a class with virtual functions, which are
+/// implemented by the methods of a Rust trait object.
+///
+/// ```ignore
+/// trait MyTrait {
+///     fn compute_value(&self, x: i32) -> i32;
+/// }
+///
+/// cpp_impl!(pub unsafe struct MyClassImpl as "MyClass" for dyn MyTrait {
+///     computeValue => fn compute_value(&self, x: i32 as "int") -> i32 as "int";
+/// });
+///
+/// let object = MyClassImpl::new(MyTraitImpl { x: 333 });
+/// let value = unsafe {
+///     cpp!([object.as_ptr() => object as "MyClass*"] -> i32 as "int" {
+///         return object->computeValue(123);
+///     })
+/// };
+/// ```
+///
+/// Each line maps a virtual function to a method of the trait. The C++ function is `const`
+/// if the method takes `&self`, and not `const` if it takes `&mut self`. The C++ types of the
+/// arguments and of the return value are inferred like the ones of the captures of `cpp!`,
+/// and the references are passed as C++ references. The arguments passed by value are moved
+/// to Rust, which drops them.
+///
+/// The generated struct owns the C++ object, which is allocated by `new` or `from_box`, and
+/// which owns the Rust object. `as_ptr` gives the pointer to the C++ object as a `void*`
+/// pointing to the base class. `into_raw` gives the same pointer, along with the ownership
+/// of the object: the C++ code must then delete it through the base class, whose destructor
+/// must be virtual. The base class must be default constructible. Several virtual functions
+/// can call the same method.
+///
+/// This macro requires the `std` feature.
+#[cfg(feature = "std")]
+#[macro_export]
+macro_rules! cpp_impl {
+    ($(#[$($attrs:tt)*])* unsafe struct $name:ident as $type:literal for $($rest:tt)*) => {
+        $crate::__cpp_impl_internal!{ [ $(#[$($attrs)*])* ] [] [unsafe struct $name as $type for $($rest)*] }
+    };
+    ($(#[$($attrs:tt)*])* pub unsafe struct $name:ident as $type:literal for $($rest:tt)*) => {
+        $crate::__cpp_impl_internal!{ [ $(#[$($attrs)*])* ] [pub] [unsafe struct $name as $type for $($rest)*] }
+    };
+    ($(#[$($attrs:tt)*])* pub($($pub:tt)*) unsafe struct $name:ident as $type:literal for $($rest:tt)*) => {
+        $crate::__cpp_impl_internal!{ [ $(#[$($attrs)*])* ] [pub($($pub)*)] [unsafe struct $name as $type for $($rest)*] }
+    };
+}
+
+/// Implementation details for cpp_impl!
+#[cfg(feature = "std")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __cpp_impl_internal {
+    ([$($attrs:tt)*] [$($vis:tt)*] [unsafe struct $name:ident $($rest:tt)*]) => {
+        $($attrs)*
+        #[derive($crate::__cpp_internal_impl)]
+        $($vis)* struct $name {
+            _ptr: [*mut ::core::ffi::c_void; 1
+                + (stringify!($($attrs)* $($vis)* unsafe struct $name $($rest)*), 0).1]
+        }
     };
 }
 
@@ -361,13 +1049,13 @@ macro_rules! cpp_class {
 #[doc(hidden)]
 #[macro_export]
 macro_rules! __cpp_class_internal {
-    (@parse [$($attrs:tt)*] [$($vis:tt)*] [unsafe struct $name:ident as $type:expr]) => {
+    (@parse [$($attrs:tt)*] [$($vis:tt)*] [unsafe struct $name:ident as $type:expr] [$($element:tt)*]) => {
         $crate::__cpp_class_internal!{@parse_attributes [ $($attrs)* ] [] [
             #[derive($crate::__cpp_internal_class)]
             #[repr(C)]
             $($vis)* struct $name {
                 _opaque : [<$name as $crate::CppTrait>::BaseType ; <$name as $crate::CppTrait>::ARRAY_SIZE
-                    + (stringify!($($attrs)* $($vis)* unsafe struct $name as $type), 0).1]
+                    + (stringify!($($attrs)* $($vis)* unsafe struct $name as $type $($element)*), 0).1]
             }
         ]}
     };
@@ -375,6 +1063,10 @@ macro_rules! __cpp_class_internal {
     (@parse_attributes [] [$($attributes:tt)*] [$($result:tt)*]) => ( $($attributes)* $($result)* );
     (@parse_attributes [#[derive($($der:ident),*)] $($tail:tt)* ] [$($attributes:tt)*] [$($result:tt)*] )
         => ($crate::__cpp_class_internal!{@parse_derive [$($der),*] @parse_attributes [$($tail)*] [ $($attributes)* ] [ $($result)* ] } );
+    (@parse_attributes [ #[cpp_fields $fields:tt] $($tail:tt)* ] [$($attributes:tt)*] [$($result:tt)*])
+        => ($crate::__cpp_class_internal!{@parse_attributes [$($tail)*] [$($attributes)*] [ $($result)* ] } );
+    (@parse_attributes [ #[cpp_index $index:tt] $($tail:tt)* ] [$($attributes:tt)*] [$($result:tt)*])
+        => ($crate::__cpp_class_internal!{@parse_attributes [$($tail)*] [$($attributes)*] [ $($result)* ] } );
     (@parse_attributes [ #[$m:meta] $($tail:tt)* ] [$($attributes:tt)*] [$($result:tt)*])
         => ($crate::__cpp_class_internal!{@parse_attributes [$($tail)*] [$($attributes)* #[$m] ] [ $($result)* ] } );
 
@@ -391,6 +1083,52 @@ macro_rules! __cpp_class_internal {
         => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
     (@parse_derive [Copy $(,$tail:ident)*] $($result:tt)*)
         => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [Add $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [Sub $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [Mul $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [Div $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [Rem $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [BitAnd $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [BitOr $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [BitXor $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [Shl $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [Shr $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [AddAssign $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [SubAssign $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [MulAssign $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [DivAssign $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [RemAssign $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [BitAndAssign $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [BitOrAssign $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [BitXorAssign $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [ShlAssign $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [ShrAssign $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [Neg $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [Index $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
+    (@parse_derive [IndexMut $(,$tail:ident)*] $($result:tt)*)
+        => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] $($result)*} );
     (@parse_derive [$i:ident $(,$tail:ident)*] @parse_attributes [$($attr:tt)*] [$($attributes:tt)*] [$($result:tt)*] )
         => ( $crate::__cpp_class_internal!{@parse_derive [$($tail),*] @parse_attributes [$($attr)*] [$($attributes)* #[derive($i)] ] [ $($result)* ] } );
 }
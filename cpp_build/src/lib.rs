@@ -21,13 +21,12 @@ mod strnom;
 
 use cpp_common::*;
 use std::collections::hash_map::{Entry, HashMap};
+use std::collections::HashSet;
 use std::env;
 use std::fs::{create_dir, remove_dir_all, File};
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 
-mod parser;
-
 fn warnln_impl(a: &str) {
     for s in a.lines() {
         println!("cargo:warning={}", s);
@@ -40,6 +39,8 @@ macro_rules! warnln {
     }
 }
 
+mod parser;
+
 // Like the write! macro, but add the #line directive (pointing to this file).
 // Note: the string literal must be on on the same line of the macro
 macro_rules! write_add_line {
@@ -57,11 +58,27 @@ const INTERNAL_CPP_STRUCTS: &str = r#"
 #include "stdint.h" // For {u}intN_t
 #include <new> // For placement new
 #include <cstdlib> // For abort
+#include <cstddef> // For offsetof
 #include <type_traits>
 #include <utility>
+#include <string>
+#include <memory>
+#include <functional>
+#include <iterator>
+#include <tuple>
+#if __cplusplus >= 201703L
+#include <string_view>
+#endif
 
 namespace rustcpp {
 
+// dynamic_cast is only valid if the base class is polymorphic
+template<typename D, typename B> D *dynamic_cast_helper(B *b, std::true_type) { return dynamic_cast<D*>(b); }
+template<typename D, typename B> D *dynamic_cast_helper(B *, std::false_type) { return nullptr; }
+template<typename D, typename B> D *dynamic_cast_helper(B *b) {
+    return dynamic_cast_helper<D>(b, std::integral_constant<bool, std::is_polymorphic<B>::value>());
+}
+
 // We can't just pass or return any type from extern "C" rust functions (because the call
 // convention may differ between the C++ type, and the Rust type).
 // So we make sure to pass trivial structure that only contains a pointer to the object we want to
@@ -106,6 +123,136 @@ template<typename T>
 typename std::enable_if<!std::is_default_constructible<T>::value>::type default_helper(void *)
 { std::abort(); }
 
+// A view of a rust string (cpp::StrView)
+struct str_view {
+    const char *data;
+    size_t length;
+    str_view() : data(nullptr), length(0) {}
+    str_view(const char *data, size_t length) : data(data), length(length) {}
+    str_view(const std::string &s) : data(s.data()), length(s.size()) {}
+    const char *begin() const { return data; }
+    const char *end() const { return data + length; }
+    size_t size() const { return length; }
+    operator std::string() const { return std::string(data, length); }
+#if __cplusplus >= 201703L
+    operator std::string_view() const { return std::string_view(data, length); }
+#endif
+};
+
+// A view of a rust slice: cpp::Slice<T> is a slice<const T>, and cpp::SliceMut<T> is a slice<T>
+template<typename T> struct slice {
+    T *data;
+    size_t length;
+    T *begin() const { return data; }
+    T *end() const { return data + length; }
+    size_t size() const { return length; }
+    T &operator[](size_t i) const { return data[i]; }
+};
+
+// An owned std::string returned to rust (cpp::CppString). The string is moved to the heap, so
+// the object can be relocated, and it is destroyed by rust.
+struct string {
+    const char *data;
+    size_t length;
+    std::string *owner;
+    void (*deleter)(void *);
+    string(std::string s) : owner(new std::string(std::move(s))), deleter(&destroy) {
+        data = owner->data();
+        length = owner->size();
+    }
+    string(const char *s) : string(std::string(s)) {}
+    string(string &&other)
+        : data(other.data), length(other.length), owner(other.owner), deleter(other.deleter) {
+        other.owner = nullptr;
+    }
+    string(const string &) = delete;
+    ~string() { delete owner; }
+    static void destroy(void *s) { delete static_cast<std::string *>(s); }
+};
+
+// A reference to a rust trait object (cpp::DynRef<dyn Trait>). Its content is opaque, and its
+// size is checked when it is captured.
+struct dyn_ref {
+    void *opaque[2];
+};
+
+// Calls of rust functions taking the arguments as an array of pointers, and the pointer to
+// the return value: the arguments passed by value are moved to rust, which drops them, and the
+// references are passed as pointers.
+template<typename T> struct owned_argument {
+    union { T value; };
+    owned_argument(T &&v) : value(std::move(v)) {}
+    ~owned_argument() {}
+    void *ptr() { return const_cast<void *>(static_cast<const void *>(&value)); }
+};
+template<typename T> struct owned_argument<T&> {
+    T *value;
+    owned_argument(T &v) : value(&v) {}
+    void *ptr() { return &value; }
+};
+template<typename T> struct return_storage {
+    union { T value; };
+    return_storage() {}
+    ~return_storage() {}
+    void *ptr() { return &value; }
+    T take() { T r(std::move(value)); value.~T(); return r; }
+};
+template<typename T> struct return_storage<T&> {
+    T *value;
+    void *ptr() { return &value; }
+    T &take() { return *value; }
+};
+template<> struct return_storage<void> {
+    char unit;
+    void *ptr() { return &unit; }
+    void take() {}
+};
+typedef void (*rust_call)(void *data, void **args, void *ret);
+template<typename R, typename... Args> R call_rust(rust_call f, void *data, Args &&... args) {
+    return_storage<R> ret;
+    void *ptrs[] = { args.ptr()..., nullptr };
+    f(data, ptrs, ret.ptr());
+    return ret.take();
+}
+
+// A boxed rust closure (cpp::CppFunction<dyn FnMut(A...) -> R>), which is destroyed by the
+// destructor unless it was moved from.
+template<typename Signature> struct function;
+template<typename R, typename... A> struct function<R(A...)> {
+    void *data;
+    rust_call call;
+    void (*deleter)(void *data);
+    function(function &&other) : data(other.data), call(other.call), deleter(other.deleter) {
+        other.data = nullptr;
+    }
+    function(const function &) = delete;
+    ~function() { if (data) deleter(data); }
+
+    R operator()(A... a) const {
+        static_assert(sizeof...(A) <= 6, "rustcpp::function has at most 6 arguments");
+        return call_rust<R>(call, data, owned_argument<A>(std::move(a))...);
+    }
+    // std::function must be copyable, so the closure is shared between the copies
+    operator std::function<R(A...)>() && {
+        std::shared_ptr<function> f = std::make_shared<function>(std::move(*this));
+        return [f](A... a) -> R { return (*f)(std::move(a)...); };
+    }
+};
+
+// The value of a cpp_const!, stored in the metadata
+template<typename T> struct const_value {
+    static_assert(std::is_integral<T>::value || std::is_enum<T>::value,
+        "cpp_const! requires an integer, a bool or an enumeration");
+    static constexpr uint64_t value(T v) { return static_cast<uint64_t>(v); }
+    static constexpr bool negative(T v) { return v < T(); }
+};
+
+// Destroys an object moved into a cpp! closure when the closure returns
+template<typename T> struct move_guard {
+    T &ref;
+    ~move_guard() { ref.~T(); }
+};
+
 template<typename T> int compare_helper(const T &a, const T&b, int cmp) {
     switch (cmp) {
         using namespace std::rel_ops;
@@ -130,6 +277,41 @@ template<typename T> int compare_helper(const T &a, const T&b, int cmp) {
     void __cpp_copy_##HASH(const void *src, void *dest) { rustcpp::copy_helper<__VA_ARGS__>(src, dest); } \
     void __cpp_default_##HASH(void *dest) { rustcpp::default_helper<__VA_ARGS__>(dest); } \
     }
+
+// The elements given to push are moved from, and destroyed, since rust forgets them
+#define RUST_CPP_VECTOR_HELPER(HASH, ...) \
+    extern "C" { \
+    size_t __cpp_vector_len_##HASH(const void *v) { return static_cast<const __VA_ARGS__*>(v)->size(); } \
+    const void *__cpp_vector_data_##HASH(const void *v) { return static_cast<const __VA_ARGS__*>(v)->data(); } \
+    void __cpp_vector_push_##HASH(void *v, void *value) { \
+        typedef __VA_ARGS__ T; typedef T::value_type V; \
+        V *x = static_cast<V*>(value); \
+        static_cast<T*>(v)->push_back(std::move(*x)); \
+        x->~V(); \
+    } \
+    }
+
+#if defined(__GNUC__) || defined(__clang__)
+#define RUST_CPP_ENUM_CHECK_BEGIN _Pragma("GCC diagnostic push") _Pragma("GCC diagnostic error \"-Wswitch\"")
+#define RUST_CPP_ENUM_CHECK_END _Pragma("GCC diagnostic pop")
+#elif defined(_MSC_VER)
+#define RUST_CPP_ENUM_CHECK_BEGIN __pragma(warning(push)) __pragma(warning(error: 4062))
+#define RUST_CPP_ENUM_CHECK_END __pragma(warning(pop))
+#else
+#define RUST_CPP_ENUM_CHECK_BEGIN
+#define RUST_CPP_ENUM_CHECK_END
+#endif
+
+#define RUST_CPP_SMART_PTR_HELPER(HASH, ...) \
+    extern "C" { \
+    const void *__cpp_ptr_get_##HASH(const void *p) { return static_cast<const __VA_ARGS__*>(p)->get(); } \
+    void __cpp_ptr_new_##HASH(void *value, void *dest) { \
+        typedef __VA_ARGS__ T; typedef T::element_type E; \
+        E *x = static_cast<E*>(value); \
+        new (dest) T(new E(std::move(*x))); \
+        x->~E(); \
+    } \
+    }
 "#;
 
 lazy_static! {
@@ -143,7 +325,78 @@ NOTE: rust-cpp's build function must be run in a build script."#
     ));
 }
 
-fn gen_cpp_lib(visitor: &parser::Parser) -> PathBuf {
+/// The C++ types of the types declared in the crate, by the last segment of their rust
+/// path. A name declared with different C++ types maps to `None`. Such a name, or the name
+/// of a type known by `infer_cpp_type`, is ambiguous when inferring a C++ type.
+#[derive(Default)]
+struct DeclaredTypes<'a>(HashMap<String, Option<&'a str>>);
+
+impl<'a> DeclaredTypes<'a> {
+    fn insert(&mut self, name: &syn::Ident, cpp: &'a str) {
+        match self.0.entry(name.to_string()) {
+            Entry::Occupied(mut e) => {
+                if *e.get() != Some(cpp) {
+                    e.insert(None);
+                }
+            }
+            Entry::Vacant(e) => {
+                e.insert(Some(cpp));
+            }
+        }
+    }
+
+    /// The C++ type to infer for a rust type of this name
+    fn lookup(&self, name: &syn::Ident) -> DeclaredType {
+        let name = name.to_string();
+        match self.0.get(&name) {
+            None => DeclaredType::Unknown,
+            Some(Some(cpp)) if !cpp_common::is_builtin_type_name(&name) => DeclaredType::Cpp(cpp.to_string()),
+            Some(_) => {
+                warnln!(
+                    "The C++ type of the rust type `{0}` cannot be inferred, since several types named `{0}` \
+                     are declared with different C++ types, or `{0}` is also a type of the cpp crate. \
+                     Add an explicit `as \"...\"`.",
+                    name
+                );
+                DeclaredType::Ambiguous
+            }
+        }
+    }
+}
+
+/// Collect the `cpp_type!` aliases, and resolve them in the signatures of the closures. They
+/// are also written to a file for the macros, which must compute the same hashes.
+fn resolve_type_aliases(visitor: &mut parser::Parser) -> HashMap<String, String> {
+    let mut type_aliases: HashMap<String, String> = HashMap::new();
+    for TypeAlias { name, cpp } in &visitor.type_aliases {
+        match type_aliases.entry(name.to_string()) {
+            Entry::Occupied(e) if e.get() != cpp => {
+                panic!(
+                    r#"
+-- rust-cpp fatal error --
+
+The C++ type alias `{}` is declared with different types: `{}` and `{}`."#,
+                    name,
+                    e.get(),
+                    cpp
+                );
+            }
+            Entry::Occupied(_) => {}
+            Entry::Vacant(e) => {
+                e.insert(cpp.clone());
+            }
+        }
+    }
+    visitor.resolve_type_aliases(&type_aliases);
+
+    let mut output = File::create(OUT_DIR.join(TYPE_ALIASES_NAME)).expect("Unable to write the C++ type aliases");
+    for (name, cpp) in &type_aliases {
+        writeln!(output, "{}={}", name, cpp).unwrap();
+    }
+    type_aliases
+}
+
+fn gen_cpp_lib(visitor: &parser::Parser, type_aliases: &HashMap<String, String>) -> PathBuf {
     let result_path = CPP_DIR.join("cpp_closures.cpp");
     let mut output = File::create(&result_path).expect("Unable to generate temporary C++ file");
 
@@ -161,23 +414,89 @@ extern "C" {{
         ).unwrap();
     }
 
-    write!(output, "{}\n\n", &visitor.snippets).unwrap();
-
     let mut hashmap = HashMap::new();
 
+    let mut classes = DeclaredTypes::default();
+    for class in &visitor.classes {
+        classes.insert(&class.name, &class.cpp);
+    }
+    let class_cpp_type = |name: &syn::Ident| classes.lookup(name);
+
     let mut sizealign = vec![];
-    for &Closure {
-        ref body_str,
-        ref sig,
-        ref callback_offset,
+
+    // The exported structs are defined before the snippets, so that they can use them.
+    // The types of their fields can only refer to the previous exported structs.
+    let mut exported = DeclaredTypes::default();
+    'structs: for st in &visitor.structs {
+        let hash = st.name_hash();
+        let mut fields = String::new();
+        for field in &st.fields {
+            let cpp = match &field.cpp {
+                Some(CppType::Literal(cpp)) => Some(cpp.clone()),
+                Some(CppType::Alias(alias)) => type_aliases.get(&alias.to_string()).map(|x| x.to_string()),
+                None => cpp_common::infer_cpp_type(&field.ty, &|name: &syn::Ident| exported.lookup(name)),
+            };
+            match cpp {
+                Some(cpp) => fields.push_str(&format!("    {} {};\n", cpp, field.name)),
+                None => {
+                    warnln!(
+                        "Cannot resolve the C++ type of the field `{}` of the cpp_export_struct! `{}`. \
+                         Specify it with `{}: ... as \"...\"`.",
+                        field.name,
+                        st.name,
+                        field.name
+                    );
+                    continue 'structs;
+                }
+            }
+        }
+        exported.insert(&st.name, &st.cpp);
+
+        // A leading `::` names the global namespace, not an anonymous one
+        let qualified = st.cpp.strip_prefix("::").unwrap_or(&st.cpp);
+        let (namespaces, name) = match qualified.rfind("::") {
+            Some(pos) => (qualified[..pos].split("::").collect::<Vec<_>>(), &qualified[pos + 2..]),
+            None => (vec![], qualified),
+        };
+        let open: String = namespaces.iter().map(|ns| format!("namespace {} {{ ", ns)).collect();
+        let close: String = namespaces.iter().map(|_| "} ").collect();
+        write!(
+            output,
+            "{open}\n{line}struct {name} {{\n{fields}}};\n{close}\n",
+            open = open,
+            line = st.line,
+            name = name,
+            fields = fields,
+            close = close
+        )
+        .unwrap();
+
+        sizealign.push(format!("{{
+                {hash}ull,
+                sizeof({type}),
+                rustcpp::AlignOf<{type}>::value,
+                rustcpp::Flags<{type}>::value
+            }}", hash=hash, type=st.cpp));
+        for field in &st.fields {
+            sizealign.push(format!(
+                "{{{hash}ull, offsetof({cpp}, {field}), sizeof({cpp}::{field}), 0}}",
+                hash = hash,
+                cpp = st.cpp,
+                field = field.name
+            ));
+        }
+    }
+
+    write!(output, "{}\n\n", &visitor.snippets).unwrap();
+
+    'closures: for Closure {
+        body_str,
+        sig,
+        callback_offset,
         ..
     } in &visitor.closures
     {
-        let &ClosureSig {
-            ref captures,
-            ref cpp,
-            ..
-        } = sig;
+        let ClosureSig { captures, cpp, .. } = sig;
 
         let hash = sig.name_hash();
         let name = sig.extern_name();
@@ -196,6 +515,52 @@ extern "C" {{
             }
         }
 
+        // Resolve the C++ types of the captures which are not explicitly given
+        let mut capture_types = Vec::with_capacity(captures.len());
+        for Capture { name, ty, cpp, .. } in captures {
+            let cpp = match cpp {
+                Some(CppType::Literal(cpp)) => Some(cpp.clone()),
+                Some(CppType::Alias(alias)) => match type_aliases.get(&alias.to_string()) {
+                    Some(cpp) => Some(cpp.to_string()),
+                    None => {
+                        warnln!(
+                            "Unknown C++ type alias `{}` for the capture `{}`. \
+                             It must be declared with cpp_type! in this crate.",
+                            alias,
+                            name
+                        );
+                        continue 'closures;
+                    }
+                },
+                None => ty
+                    .as_ref()
+                    .and_then(|ty| cpp_common::infer_cpp_type(ty, &class_cpp_type)),
+            };
+            match cpp {
+                Some(cpp) => capture_types.push(cpp),
+                None => {
+                    if ty.is_some() {
+                        warnln!(
+                            "Cannot infer the C++ type of the capture `{}`: only the primitive types, \
+                             the pointers to them, and the cpp_class! of this crate are known. \
+                             Specify it explicitly with `{} as \"...\"`.",
+                            name,
+                            name
+                        );
+                    } else {
+                        warnln!(
+                            "Cannot infer the C++ type of the capture `{}` without its rust type. \
+                             Specify it with `{}: ...`, or specify the C++ type with `{} as \"...\"`.",
+                            name,
+                            name,
+                            name
+                        );
+                    }
+                    continue 'closures;
+                }
+            }
+        }
+
         let is_void = cpp == "void";
 
         // Generate the sizes array with the sizes of each of the argument types
@@ -213,7 +578,7 @@ extern "C" {{
                 rustcpp::Flags<{type}>::value | {callback_offset}ull << 32
             }}", hash=hash, type=cpp, callback_offset = callback_offset));
         }
-        for &Capture { ref cpp, .. } in captures {
+        for cpp in &capture_types {
             sizealign.push(format!("{{
                 {hash}ull,
                 sizeof({type}),
@@ -221,17 +586,37 @@ extern "C" {{
                 rustcpp::Flags<{type}>::value
             }}", hash=hash, type=cpp));
         }
+        // The signature of an explicit rustcpp::function is checked against the CppFunction
+        for (capture, cpp) in captures.iter().zip(&capture_types) {
+            if capture.cpp.is_some() && is_function_type(cpp) {
+                for i in 0..FUNCTION_SIGNATURE_RECORDS {
+                    sizealign.push(format!("{{
+                {hash}ull,
+                rustcpp::FunctionLayout<{type}, {i}>::size,
+                rustcpp::FunctionLayout<{type}, {i}>::align,
+                rustcpp::FunctionLayout<{type}, {i}>::flags
+            }}", hash=hash, type=cpp, i=i));
+                }
+            }
+        }
 
         // Generate the parameters and function declaration
         let params = captures
             .iter()
+            .zip(&capture_types)
             .map(
-                |&Capture {
-                     mutable,
-                     ref name,
-                     ref cpp,
-                 }| {
-                    if mutable {
+                |(
+                    &Capture {
+                        moved,
+                        mutable,
+                        ref name,
+                        ..
+                    },
+                    cpp,
+                )| {
+                    if moved {
+                        format!("{} && {}", cpp, name)
+                    } else if mutable {
                         format!("{} & {}", cpp, name)
                     } else {
                         format!("{} const& {}", cpp, name)
@@ -241,30 +626,51 @@ extern "C" {{
             .collect::<Vec<_>>()
             .join(", ");
 
+        // The objects moved into the closure are owned by it, and destroyed when it returns
+        let guards = captures
+            .iter()
+            .zip(&capture_types)
+            .filter(|(capture, _)| capture.moved)
+            .map(|(Capture { name, .. }, cpp)| {
+                format!(
+                    "rustcpp::move_guard<{cpp}> __rust_cpp_move_guard_{name}{{{name}}};\n",
+                    cpp = cpp,
+                    name = name
+                )
+            })
+            .collect::<String>();
+
         if is_void {
             #[rustfmt::skip]
             write_add_line!(output, r#"
 extern "C" {{
 void {name}({params}) {{
-{body}
+{guards}{body}
 }}
 }}
 "#,
                 name = &name,
                 params = params,
+                guards = guards,
                 body = body_str
             ).unwrap();
         } else {
             let comma = if params.is_empty() { "" } else { "," };
             let args = captures
                 .iter()
-                .map(|&Capture { ref name, .. }| name.to_string())
+                .map(|Capture { name, moved, .. }| {
+                    if *moved {
+                        format!("std::move({})", name)
+                    } else {
+                        name.to_string()
+                    }
+                })
                 .collect::<Vec<_>>()
                 .join(", ");
             #[rustfmt::skip]
             write_add_line!(output, r#"
 static inline {ty} {name}_impl({params}) {{
-{body}
+{guards}{body}
 }}
 extern "C" {{
 void {name}({params}{comma} void* __result) {{
@@ -277,6 +683,7 @@ void {name}({params}{comma} void* __result) {{
                 comma = comma,
                 ty = cpp,
                 args = args,
+                guards = guards,
                 body = body_str
             ).unwrap();
         }
@@ -293,6 +700,80 @@ void {name}({params}{comma} void* __result) {{
                 rustcpp::Flags<{type}>::value
             }}", hash=hash, type=class.cpp));
 
+        // The type of the elements is checked against the rust type
+        if let Some((wrapper, _)) = &class.element {
+            let element = match wrapper {
+                ClassWrapper::Vector => format!("{}::value_type", class.cpp),
+                ClassWrapper::UniquePtr | ClassWrapper::SharedPtr => {
+                    format!("{}::element_type", class.cpp)
+                }
+            };
+            sizealign.push(format!("{{
+                {hash}ull,
+                sizeof({type}),
+                rustcpp::AlignOf<{type}>::value,
+                rustcpp::Flags<{type}>::value
+            }}", hash=hash, type=element));
+        }
+
+        // The offset and size of the declared fields. The type is named by a typedef, as
+        // the offsetof macro would not accept a template with several arguments.
+        if !class.fields.is_empty() {
+            write!(
+                output,
+                "{line}typedef {cpp_name} rust_cpp_fields_{hash};\n",
+                line = class.line,
+                hash = hash,
+                cpp_name = class.cpp
+            )
+            .unwrap();
+        }
+        for field in &class.fields {
+            sizealign.push(format!(
+                "{{{hash}ull, offsetof(rust_cpp_fields_{hash}, {field}), sizeof(rust_cpp_fields_{hash}::{field}), 0}}",
+                hash = hash,
+                field = field.name
+            ));
+        }
+
+        // The base class is the cpp_class! of the given name. When it is not found, a
+        // record of size 0 keeps the position of the next records, and the macro reports it.
+        if let Some(base) = &class.base {
+            let base_name = match base {
+                syn::Type::Path(p) => p.path.segments.last().map(|s| &s.ident),
+                _ => None,
+            };
+            let base_cpp = base_name.and_then(|name| classes.0.get(&name.to_string()));
+            match base_cpp {
+                Some(Some(base_cpp)) => {
+                    sizealign.push(format!("{{
+                {hash}ull,
+                sizeof({type}),
+                rustcpp::AlignOf<{type}>::value,
+                rustcpp::Flags<{type}>::value
+            }}", hash=hash, type=base_cpp));
+                    write!(output,
+                        "{line}extern \"C\" {base} *__cpp_upcast_{hash}({name} *d) {{ return static_cast<{base}*>(d); }}\n\
+                         {line}extern \"C\" {name} *__cpp_downcast_{hash}({base} *b) {{ return rustcpp::dynamic_cast_helper<{name}>(b); }}\n",
+                        line = class.line, hash = hash, name = class.cpp, base = base_cpp).unwrap();
+                }
+                Some(None) => {
+                    warnln!(
+                        "The base class of the cpp_class! `{}` is ambiguous: several cpp_class! with different C++ types have its name.",
+                        class.name
+                    );
+                    sizealign.push(format!("{{{}ull, 0, 0, 0}}", hash));
+                }
+                None => {
+                    warnln!(
+                        "The base class of the cpp_class! `{}` is not the name of a cpp_class!.",
+                        class.name
+                    );
+                    sizealign.push(format!("{{{}ull, 0, 0, 0}}", hash));
+                }
+            }
+        }
+
         // Generate helper function.
         // (this is done in a macro, which right after a #line directing pointing to the location of
         // the cpp_class! macro in order to give right line information in the possible errors)
@@ -305,6 +786,26 @@ void {name}({params}{comma} void* __result) {{
         )
         .unwrap();
 
+        match class.element {
+            Some((ClassWrapper::Vector, _)) => write!(
+                output,
+                "{line}RUST_CPP_VECTOR_HELPER({hash}, {cpp_name})\n",
+                line = class.line,
+                hash = hash,
+                cpp_name = class.cpp
+            )
+            .unwrap(),
+            Some((ClassWrapper::UniquePtr, _)) | Some((ClassWrapper::SharedPtr, _)) => write!(
+                output,
+                "{line}RUST_CPP_SMART_PTR_HELPER({hash}, {cpp_name})\n",
+                line = class.line,
+                hash = hash,
+                cpp_name = class.cpp
+            )
+            .unwrap(),
+            None => {}
+        }
+
         if class.derives("PartialEq") {
             write!(output,
                 "{line}extern \"C\" bool __cpp_equal_{hash}(const {name} *a, const {name} *b) {{ return *a == *b; }}\n",
@@ -315,6 +816,386 @@ void {name}({params}{comma} void* __result) {{
                 "{line}extern \"C\" bool __cpp_compare_{hash}(const {name} *a, const {name} *b, int cmp) {{ return rustcpp::compare_helper(*a, *b, cmp); }}\n",
                 line = class.line, hash = hash, name = class.cpp).unwrap();
         }
+        for (derive, method, op, kind) in CLASS_OPERATORS {
+            if !class.derives(derive) {
+                continue;
+            }
+            match kind {
+                OperatorKind::Unary => write!(output,
+                    "{line}extern \"C\" void __cpp_op_{method}_{hash}(const {name} *a, {name} *r) {{ ::new (r) {name}({op}*a); }}\n",
+                    line = class.line, method = method, hash = hash, name = class.cpp, op = op).unwrap(),
+                OperatorKind::Binary => write!(output,
+                    "{line}extern \"C\" void __cpp_op_{method}_{hash}(const {name} *a, const {name} *b, {name} *r) {{ ::new (r) {name}(*a {op} *b); }}\n",
+                    line = class.line, method = method, hash = hash, name = class.cpp, op = op).unwrap(),
+                OperatorKind::Assign => write!(output,
+                    "{line}extern \"C\" void __cpp_op_{method}_{hash}({name} *a, const {name} *b) {{ *a {op} *b; }}\n",
+                    line = class.line, method = method, hash = hash, name = class.cpp, op = op).unwrap(),
+            }
+        }
+        if class.derives("Index") || class.derives("IndexMut") {
+            // The element type is checked against the rust type, so no record is generated
+            // when the index type is unknown, and the macro reports the error.
+            let idx = class
+                .index
+                .as_ref()
+                .and_then(|(idx, _)| cpp_common::infer_cpp_type(idx, &class_cpp_type));
+            match idx {
+                Some(idx) => {
+                    write!(output,
+                        "{line}typedef std::decay<decltype(std::declval<const {name}&>()[std::declval<{idx}>()])>::type rust_cpp_index_{hash};\n\
+                         {line}extern \"C\" const void *__cpp_index_{hash}(const {name} *a, const {idx} *i) {{ \
+                         static_assert(std::is_lvalue_reference<decltype((*a)[*i])>::value, \"operator[] must return a reference\"); \
+                         return &(*a)[*i]; }}\n",
+                        line = class.line, hash = hash, name = class.cpp, idx = idx).unwrap();
+                    if class.derives("IndexMut") {
+                        write!(output,
+                            "{line}extern \"C\" void *__cpp_index_mut_{hash}({name} *a, const {idx} *i) {{ \
+                             static_assert(std::is_lvalue_reference<decltype((*a)[*i])>::value, \"operator[] must return a reference\"); \
+                             return &(*a)[*i]; }}\n",
+                            line = class.line, hash = hash, name = class.cpp, idx = idx).unwrap();
+                    }
+                    sizealign.push(format!("{{
+                {hash}ull,
+                sizeof({type}),
+                rustcpp::AlignOf<{type}>::value,
+                rustcpp::Flags<{type}>::value
+            }}", hash=hash, type=format!("rust_cpp_index_{}", hash)));
+                }
+                None if class.index.is_some() => {
+                    warnln!(
+                        "Cannot resolve the C++ type of the index of the cpp_class! `{}`. Use a primitive type or a cpp_class!.",
+                        class.name
+                    );
+                }
+                None => {}
+            }
+        }
+    }
+
+    'impls: for imp in &visitor.impls {
+        let hash = imp.name_hash();
+
+        // The C++ types of the arguments and return value, which may be references
+        let resolve = |ty: &syn::Type, cpp: &Option<CppType>| -> Option<String> {
+            match cpp {
+                Some(CppType::Literal(cpp)) => Some(cpp.clone()),
+                Some(CppType::Alias(alias)) => type_aliases.get(&alias.to_string()).map(|x| x.to_string()),
+                None => match ty {
+                    syn::Type::Reference(r) => {
+                        let elem = cpp_common::infer_cpp_type(&r.elem, &class_cpp_type)?;
+                        if r.mutability.is_some() {
+                            Some(format!("{} &", elem))
+                        } else {
+                            Some(format!("{} const&", elem))
+                        }
+                    }
+                    _ => cpp_common::infer_cpp_type(ty, &class_cpp_type),
+                },
+            }
+        };
+        let mut methods = vec![];
+        for method in &imp.methods {
+            let mut args = vec![];
+            for (arg, ty, cpp) in &method.args {
+                match resolve(ty, cpp) {
+                    Some(cpp) => args.push(cpp),
+                    None => {
+                        warnln!(
+                            "Cannot resolve the C++ type of the argument `{}` of `{}` in the cpp_impl! `{}`. \
+                             Specify it with `{}: ... as \"...\"`.",
+                            arg,
+                            method.name,
+                            imp.name,
+                            arg
+                        );
+                        continue 'impls;
+                    }
+                }
+            }
+            let ret = match &method.ret {
+                None => "void".to_owned(),
+                Some((ty, cpp)) => match resolve(ty, cpp) {
+                    Some(cpp) => cpp,
+                    None => {
+                        warnln!(
+                            "Cannot resolve the C++ return type of `{}` in the cpp_impl! `{}`. \
+                             Specify it with `-> ... as \"...\"`.",
+                            method.name,
+                            imp.name
+                        );
+                        continue 'impls;
+                    }
+                },
+            };
+            methods.push((method, args, ret));
+        }
+
+        let class = format!("rust_cpp_impl_{}", hash);
+        sizealign.push(format!("{{
+                {hash}ull,
+                sizeof({type}),
+                rustcpp::AlignOf<{type}>::value,
+                rustcpp::Flags<{type}>::value
+            }}", hash=hash, type=class));
+
+        // The subclass owns the rust object, and forwards the virtual functions to the
+        // functions exported by the cpp_impl! macro
+        let drop_symbol = rust_callback_symbol(&syn::Ident::new(
+            &format!("impl_{}_drop", hash),
+            proc_macro2::Span::call_site(),
+        ));
+        let mut decls = String::new();
+        let mut defs = String::new();
+        for (index, (method, args, ret)) in methods.iter().enumerate() {
+            let symbol = rust_callback_symbol(&syn::Ident::new(
+                &format!("impl_{}_method_{}_{}", hash, index, method.name),
+                proc_macro2::Span::call_site(),
+            ));
+            let params = args
+                .iter()
+                .enumerate()
+                .map(|(i, cpp)| format!("{} a{}", cpp, i))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let call_args: String = args
+                .iter()
+                .enumerate()
+                .map(|(i, cpp)| {
+                    format!(
+                        ", rustcpp::owned_argument<{cpp}>(std::forward<{cpp}>(a{i}))",
+                        cpp = cpp,
+                        i = i
+                    )
+                })
+                .collect();
+            let constness = if method.mutable { "" } else { " const" };
+            decls.push_str(&format!(
+                "    {ret} {name}({params}){constness} override;\n",
+                ret = ret,
+                name = method.cpp_name,
+                params = params,
+                constness = constness
+            ));
+            defs.push_str(&format!(
+                "extern \"C\" void {symbol}(void *, void **, void *);\n\
+                 {ret} {class}::{name}({params}){constness} {{ \
+                 return rustcpp::call_rust<{ret}>(&{symbol}, rust_object{call_args}); }}\n",
+                symbol = symbol,
+                ret = ret,
+                class = class,
+                name = method.cpp_name,
+                params = params,
+                constness = constness,
+                call_args = call_args
+            ));
+        }
+        #[rustfmt::skip]
+        write!(output, r#"{line}struct {class} final : {base} {{
+    void *rust_object;
+    explicit {class}(void *o) : rust_object(o) {{}}
+    {class}(const {class} &) = delete;
+    ~{class}();
+{decls}}};
+extern "C" void {drop_symbol}(void *);
+{class}::~{class}() {{ {drop_symbol}(rust_object); }}
+{defs}extern "C" {{
+void *__cpp_impl_new_{hash}(void *o) {{ return static_cast<{base}*>(new {class}(o)); }}
+void __cpp_impl_delete_{hash}(void *p) {{ delete static_cast<{class}*>(static_cast<{base}*>(p)); }}
+}}
+"#,
+            line = imp.line,
+            class = class,
+            base = imp.cpp,
+            decls = decls,
+            defs = defs,
+            drop_symbol = drop_symbol,
+            hash = hash
+        ).unwrap();
+    }
+
+    // The expression initializes a constexpr variable, so that the compiler fails if it is
+    // not a constant expression: the metadata itself is initialized at run time.
+    let mut const_hashes = HashSet::new();
+    for c in &visitor.consts {
+        let hash = c.name_hash();
+        if !const_hashes.insert(hash) {
+            continue;
+        }
+        write!(
+            output,
+            "{line}constexpr auto rust_cpp_const_value_{hash} = ({cpp});\n\
+             typedef rustcpp::const_value<std::decay<decltype(rust_cpp_const_value_{hash})>::type> rust_cpp_const_{hash};\n",
+            line = c.line,
+            cpp = c.cpp,
+            hash = hash
+        )
+        .unwrap();
+        sizealign.push(format!(
+            "{{{hash}ull, rust_cpp_const_{hash}::value(rust_cpp_const_value_{hash}), 0, \
+             uint64_t(rust_cpp_const_{hash}::negative(rust_cpp_const_value_{hash})) << {flag_is_negative}}}",
+            hash = hash,
+            flag_is_negative = flags::IS_NEGATIVE
+        ));
+    }
+
+    for e in &visitor.enums {
+        let hash = e.name_hash();
+        sizealign.push(format!("{{
+                {hash}ull,
+                sizeof({type}),
+                rustcpp::AlignOf<{type}>::value,
+                rustcpp::Flags<{type}>::value
+            }}", hash=hash, type=format!("std::underlying_type<{}>::type", e.cpp)));
+        for variant in &e.variants {
+            sizealign.push(format!(
+                "{{{hash}ull, rustcpp::const_value<{cpp}>::value({cpp}::{variant}), 0, \
+                 uint64_t(rustcpp::const_value<{cpp}>::negative({cpp}::{variant})) << {flag_is_negative}}}",
+                hash = hash,
+                cpp = e.cpp,
+                variant = variant,
+                flag_is_negative = flags::IS_NEGATIVE
+            ));
+        }
+        // The enumerators which are not handled in the switch are a compiler error, so the
+        // build fails if some are missing on the rust side, as their values would be invalid
+        let cases: String = e
+            .variants
+            .iter()
+            .map(|v| format!("case {}::{}: ", e.cpp, v))
+            .collect();
+        write!(
+            output,
+            "RUST_CPP_ENUM_CHECK_BEGIN\n\
+             {line}inline void rust_cpp_enum_check_{hash}({cpp} e) {{ switch (e) {{ {cases}break; }} }}\n\
+             RUST_CPP_ENUM_CHECK_END\n",
+            line = e.line,
+            hash = hash,
+            cpp = e.cpp,
+            cases = cases
+        )
+        .unwrap();
+    }
+
+    // The iterators of the container are stored in an opaque buffer on the rust side. The
+    // container is const, since the rust side only borrows it immutably.
+    // Each element of the items is written at the address given by the rust side: a copy,
+    // or a pointer to the element in the container.
+    let mut iter_hashes = HashSet::new();
+    for it in &visitor.iters {
+        let hash = it.name_hash();
+        if !iter_hashes.insert(hash) {
+            continue;
+        }
+        let state = format!("rust_cpp_iter_{}", hash);
+        let elements = it.elements();
+        let element_expr = |i: usize| {
+            if it.is_tuple() {
+                format!("std::get<{}>(*s.it)", i)
+            } else {
+                "*s.it".to_owned()
+            }
+        };
+        let mut typedefs = String::new();
+        let mut writes = String::new();
+        for (i, (_, by_ref)) in elements.iter().enumerate() {
+            let expr = element_expr(i);
+            typedefs.push_str(&format!(
+                "    typedef std::decay<decltype({})>::type e{}_t;\n",
+                expr.replace("*s.it", "*std::declval<iterator_t&>()"),
+                i
+            ));
+            if *by_ref {
+                writes.push_str(&format!(
+                    "    static_assert(std::is_lvalue_reference<decltype({expr})>::value, \
+                     \"the element {i} of the items is not a reference\");\n    \
+                     *static_cast<const void**>(out[{i}]) = std::addressof({expr});\n",
+                    expr = expr,
+                    i = i
+                ));
+            } else {
+                writes.push_str(&format!(
+                    "    ::new (out[{i}]) {state}::e{i}_t({expr});\n",
+                    state = state,
+                    expr = expr,
+                    i = i
+                ));
+            }
+        }
+        write!(
+            output,
+            "{line}struct {state} {{
+    typedef const std::remove_reference<{cpp}>::type container_t;
+    typedef decltype(std::begin(std::declval<container_t&>())) iterator_t;
+    typedef decltype(std::end(std::declval<container_t&>())) sentinel_t;
+{typedefs}    iterator_t it;
+    sentinel_t end;
+}};
+extern \"C\" {{
+void __cpp_iter_begin_{hash}(void *c, void *p) {{
+    {state}::container_t &container = *static_cast<{state}::container_t*>(c);
+    ::new (p) {state}{{std::begin(container), std::end(container)}};
+}}
+bool __cpp_iter_next_{hash}(void *p, void **out) {{
+    {state} &s = *static_cast<{state}*>(p);
+    if (s.it == s.end) return false;
+{writes}    ++s.it;
+    return true;
+}}
+void __cpp_iter_drop_{hash}(void *p) {{ static_cast<{state}*>(p)->~{state}(); }}
+}}
+",
+            line = it.line,
+            state = state,
+            cpp = it.cpp,
+            typedefs = typedefs,
+            writes = writes,
+            hash = hash
+        )
+        .unwrap();
+
+        sizealign.push(format!(
+            "{{{hash}ull, sizeof({state}), rustcpp::AlignOf<{state}>::value, 0}}",
+            hash = hash,
+            state = state
+        ));
+        let container = format!("std::remove_cv<{}::container_t>::type", state);
+        let types = std::iter::once(container)
+            .chain((0..elements.len()).map(|i| format!("{}::e{}_t", state, i)));
+        for ty in types {
+            sizealign.push(format!("{{
+                {hash}ull,
+                sizeof({type}),
+                rustcpp::AlignOf<{type}>::value,
+                rustcpp::Flags<{type}>::value
+            }}", hash=hash, type=ty));
+        }
+    }
+
+    // The macros from the excluded files which are not also in the compiled files
+    let item_hashes: HashSet<u64> = visitor
+        .classes
+        .iter()
+        .map(Class::name_hash)
+        .chain(visitor.impls.iter().map(Impl::name_hash))
+        .chain(visitor.consts.iter().map(Const::name_hash))
+        .chain(visitor.enums.iter().map(Enum::name_hash))
+        .chain(visitor.structs.iter().map(ExportedStruct::name_hash))
+        .chain(iter_hashes.iter().cloned())
+        .collect();
+    let excluded_hashes: HashSet<u64> = visitor
+        .excluded_closures
+        .iter()
+        .map(ClosureSig::name_hash)
+        .chain(visitor.excluded_hashes.iter().cloned())
+        .collect();
+    for hash in &excluded_hashes {
+        if !hashmap.contains_key(hash) && !item_hashes.contains(hash) {
+            sizealign.push(format!(
+                "{{{hash}ull, 0, 0, 1ull << {flag_is_excluded}}}",
+                hash = hash,
+                flag_is_excluded = flags::IS_EXCLUDED
+            ));
+        }
     }
 
     let mut magic = vec![];
@@ -346,9 +1227,41 @@ struct Flags {{
         (std::is_trivially_copyable<T>::value << {flag_is_trivially_copyable}) |
         (std::is_trivially_default_constructible<T>::value << {flag_is_trivially_default_constructible}) |
 #endif
+        (std::is_integral<T>::value << {flag_is_integral}) |
+        (std::is_signed<T>::value << {flag_is_signed}) |
+        (std::is_floating_point<T>::value << {flag_is_floating_point}) |
+        (std::is_pointer<T>::value << {flag_is_pointer}) |
+        (std::is_reference<T>::value << {flag_is_reference}) |
+        (std::is_polymorphic<T>::value << {flag_is_polymorphic}) |
         0;
 }};
 
+template<typename T> struct LayoutOf {{
+    static const uint64_t size = sizeof(T);
+    static const uint64_t align = AlignOf<T>::value;
+}};
+template<> struct LayoutOf<void> {{
+    static const uint64_t size = 0;
+    static const uint64_t align = 1;
+}};
+
+template<unsigned I, typename... T> struct NthType {{ typedef void type; }};
+template<typename T, typename... U> struct NthType<0, T, U...> {{ typedef T type; }};
+template<unsigned I, typename T, typename... U> struct NthType<I, T, U...> {{
+    typedef typename NthType<I - 1, U...>::type type;
+}};
+
+// The layouts of the return type (I = 0) and of the arguments of a rustcpp::function. The
+// flags of the return type are the number of arguments, and the records past the arguments
+// are empty.
+template<typename F, unsigned I> struct FunctionLayout;
+template<typename R, typename... A, unsigned I> struct FunctionLayout<function<R(A...)>, I> {{
+    typedef typename NthType<I, R, A...>::type type;
+    static const uint64_t size = I <= sizeof...(A) ? LayoutOf<type>::size : 0;
+    static const uint64_t align = I <= sizeof...(A) ? LayoutOf<type>::align : 0;
+    static const uint64_t flags = I == 0 ? sizeof...(A) : 0;
+}};
+
 struct SizeAlign {{
     uint64_t hash;
     uint64_t size;
@@ -387,6 +1300,12 @@ MetaData
         flag_is_trivially_destructible = flags::IS_TRIVIALLY_DESTRUCTIBLE,
         flag_is_trivially_copyable = flags::IS_TRIVIALLY_COPYABLE,
         flag_is_trivially_default_constructible = flags::IS_TRIVIALLY_DEFAULT_CONSTRUCTIBLE,
+        flag_is_integral = flags::IS_INTEGRAL,
+        flag_is_signed = flags::IS_SIGNED,
+        flag_is_floating_point = flags::IS_FLOATING_POINT,
+        flag_is_pointer = flags::IS_POINTER,
+        flag_is_reference = flags::IS_REFERENCE,
+        flag_is_polymorphic = flags::IS_POLYMORPHIC,
     ).unwrap();
 
     result_path
@@ -421,6 +1340,7 @@ Failed to create output object directory."#,
 pub struct Config {
     cc: cc::Build,
     std_flag_set: bool, // true if the -std flag was specified
+    filter: parser::PathFilter,
 }
 
 impl Default for Config {
@@ -439,9 +1359,38 @@ impl Config {
         Config {
             cc,
             std_flag_set: false,
+            filter: Default::default(),
         }
     }
 
+    /// Do not compile the `cpp!` and `cpp_class!` macros of the files matching the
+    /// given glob, nor of their sub-modules. The glob is relative to the package
+    /// directory: `*` and `?` do not match `/`, `**` matches any number of
+    /// directories, and a glob matching a directory matches all the files it contains.
+    ///
+    /// This is useful for modules which contain things that look like `cpp!` macros, or
+    /// which are not compiled in some configurations. Using a `cpp!` macro from an
+    /// excluded file is an error.
+    ///
+    /// ```no_run
+    /// cpp_build::Config::new()
+    ///     .exclude("src/vendor")
+    ///     .exclude("src/**/*_generated.rs")
+    ///     .build("src/lib.rs");
+    /// ```
+    pub fn exclude(&mut self, path_glob: &str) -> &mut Self {
+        self.filter.exclude(path_glob);
+        self
+    }
+
+    /// Only compile the `cpp!` and `cpp_class!` macros of the files matching one of the
+    /// globs given to this function. The syntax of the glob is the same as for `exclude`.
+    /// The other files are still parsed to find the modules they declare.
+    pub fn only(&mut self, path_glob: &str) -> &mut Self {
+        self.filter.only(path_glob);
+        self
+    }
+
     /// Add a directory to the `-I` or include path for headers
     pub fn include<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
         self.cc.include(dir);
@@ -633,8 +1582,29 @@ impl Config {
     ///
     /// This method may technically be called more than once for ergonomic
     /// reasons, but that usually won't do what you want. Use a different
-    /// `Config` object each time you want to build a crate.
+    /// `Config` object each time you want to build a crate, or use
+    /// `build_roots` to build several targets of the package.
     pub fn build<P: AsRef<Path>>(&mut self, crate_root: P) {
+        self.build_roots([crate_root])
+    }
+
+    /// Same as `build`, but extracts the `cpp` declarations from several crate
+    /// roots, and builds them all in the same static library.
+    ///
+    /// All the targets of a package (library, binaries, integration tests,
+    /// examples and benchmarks) share the same build script, so this allows
+    /// using `cpp!` in all of them. If the package has a library, cargo only
+    /// links the static library with it, so the other targets must use the
+    /// library crate.
+    ///
+    /// ```no_run
+    /// cpp_build::Config::new().build_roots(["src/lib.rs", "src/main.rs", "tests/foo.rs"]);
+    /// ```
+    pub fn build_roots<I, P>(&mut self, crate_roots: I)
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
         assert_eq!(
             env!("CARGO_PKG_VERSION"),
             VERSION,
@@ -644,20 +1614,30 @@ impl Config {
         // Clean up any leftover artifacts
         clean_artifacts();
 
-        // Parse the crate
+        // Parse the crates
         let mut visitor = parser::Parser::default();
-        if let Err(err) = visitor.parse_crate(&crate_root) {
-            warnln!(r#"-- rust-cpp parse error --
+        visitor.filter = self.filter.clone();
+        for crate_root in crate_roots {
+            if let Err(err) = visitor.parse_crate(crate_root) {
+                warnln!(r#"-- rust-cpp parse error --
 There was an error parsing the crate for the rust-cpp build script:
 {}
 In order to provide a better error message, the build script will exit successfully, such that rustc can provide an error message."#,
-                    err
-                );
-            return;
+                        err
+                    );
+                return;
+            }
+        }
+
+        let mut output = File::create(OUT_DIR.join(EXPANSION_ERRORS_NAME))
+            .expect("Unable to write the macro expansion errors");
+        for error in &visitor.expansion_errors {
+            writeln!(output, "{}", error.replace('\n', " ")).unwrap();
         }
 
         // Generate the C++ library code
-        let filename = gen_cpp_lib(&visitor);
+        let type_aliases = resolve_type_aliases(&mut visitor);
+        let filename = gen_cpp_lib(&visitor, &type_aliases);
 
         // Ensure C++11 mode is enabled. We rely on some C++11 construct, so we
         // must enable C++11 by default.
@@ -1,13 +1,20 @@
-use cpp_common::{Class, Closure, Macro, RustInvocation};
+use cpp_common::{
+    rust_callback_symbol, Class, Closure, ClosureSig, Const, Enum, ExportedStruct, Impl, Iter,
+    Macro, RustInvocation, TypeAlias,
+};
 use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::env;
 use std::fmt;
 use std::fs::File;
 use std::io::Read;
 use std::mem::swap;
-use std::path::{Path, PathBuf};
-use syn;
+use std::path::{Component, Path, PathBuf};
+use syn::punctuated::Punctuated;
 use syn::visit::Visit;
+use syn::Token;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum Error {
     ParseCannotOpenFile {
@@ -21,6 +28,12 @@ pub enum Error {
         src_path: String,
         line: u32,
     },
+    MacroExpansionError {
+        src_path: String,
+        line: u32,
+        name: String,
+        error: String,
+    },
 }
 
 impl fmt::Display for Error {
@@ -37,6 +50,19 @@ impl fmt::Display for Error {
                 ref src_path,
                 ref line,
             } => write!(f, "{}:{}: Lexing error", src_path, line + 1),
+            Error::MacroExpansionError {
+                ref src_path,
+                ref line,
+                ref name,
+                ref error,
+            } => write!(
+                f,
+                "{}:{}: Cannot expand `{}!` to find the cpp! macros it contains: {}",
+                src_path,
+                line + 1,
+                name,
+                error
+            ),
         }
     }
 }
@@ -119,8 +145,9 @@ fn expand_sub_rust_macro(input: String, mut t: ExpandSubMacroType) -> Result<Str
             ::syn::parse2::<RustInvocation>(input).map_err(|e| LineError(line, e.to_string()))?;
         let fn_name = match t {
             ExpandSubMacroType::Lit => {
-                extra_decl.push_str(&format!("extern \"C\" void {}();\n", rust_invocation.id));
-                rust_invocation.id.clone().to_string()
+                let symbol = rust_callback_symbol(&rust_invocation.id);
+                extra_decl.push_str(&format!("extern \"C\" void {}();\n", symbol));
+                symbol
             }
             ExpandSubMacroType::Closure(ref mut offset) => {
                 use cpp_common::FILE_HASH;
@@ -136,12 +163,12 @@ fn expand_sub_rust_macro(input: String, mut t: ExpandSubMacroType) -> Result<Str
         let mut decl_types = rust_invocation
             .arguments
             .iter()
-            .map(|&(_, ref val)| format!("rustcpp::argument_helper<{}>::type", val))
+            .map(|(_, val)| format!("rustcpp::argument_helper<{}>::type", val))
             .collect::<Vec<_>>();
         let mut call_args = rust_invocation
             .arguments
             .iter()
-            .map(|&(ref val, _)| val.to_string())
+            .map(|(val, _)| val.to_string())
             .collect::<Vec<_>>();
 
         let fn_call = match rust_invocation.return_type {
@@ -182,20 +209,27 @@ fn expand_sub_rust_macro(input: String, mut t: ExpandSubMacroType) -> Result<Str
 
 #[test]
 fn test_expand_sub_rust_macro() {
+    let xxx = rust_callback_symbol(&syn::parse_str("xxx").unwrap());
+    let yyy = rust_callback_symbol(&syn::parse_str("yyy").unwrap());
+    assert!(xxx.starts_with("rust_cpp_cpp_build_") && xxx.ends_with("_xxx"));
+
     let x = expand_sub_rust_macro(
         "{ rust!(xxx [] { 1 }); }".to_owned(),
         ExpandSubMacroType::Lit,
     );
     assert_eq!(
         x.unwrap(),
-        "extern \"C\" void xxx();\n{ reinterpret_cast<void (*)()>(xxx)(); }"
+        format!(
+            "extern \"C\" void {xxx}();\n{{ reinterpret_cast<void (*)()>({xxx})(); }}",
+            xxx = xxx
+        )
     );
 
     let x = expand_sub_rust_macro(
         "{ hello( rust!(xxx [] { 1 }), rust!(yyy [] { 2 }); ) }".to_owned(),
         ExpandSubMacroType::Lit,
     );
-    assert_eq!(x.unwrap(), "extern \"C\" void xxx();\nextern \"C\" void yyy();\n{ hello( reinterpret_cast<void (*)()>(xxx)(), reinterpret_cast<void (*)()>(yyy)(); ) }");
+    assert_eq!(x.unwrap(), format!("extern \"C\" void {xxx}();\nextern \"C\" void {yyy}();\n{{ hello( reinterpret_cast<void (*)()>({xxx})(), reinterpret_cast<void (*)()>({yyy})(); ) }}", xxx = xxx, yyy = yyy));
 
     let s = "{ /* rust! */  /* rust!(xxx [] { 1 }) */ }".to_owned();
     assert_eq!(
@@ -204,10 +238,10 @@ fn test_expand_sub_rust_macro() {
     );
 }
 
-#[path = "strnom.rs"]
-mod strnom;
 use crate::strnom::*;
 
+mod mbe;
+
 fn skip_literal(mut input: Cursor) -> PResult<bool> {
     //input = whitespace(input)?.0;
     if input.starts_with("\"") {
@@ -246,7 +280,7 @@ fn skip_literal(mut input: Cursor) -> PResult<bool> {
     Ok((input, false))
 }
 
-fn new_cursor(s: &str) -> Cursor {
+fn new_cursor(s: &str) -> Cursor<'_> {
     Cursor {
         rest: s,
         off: 0,
@@ -290,7 +324,7 @@ fn find_delimited<'a>(mut input: Cursor<'a>, needle: &str) -> PResult<'a, ()> {
         }
         if stack.is_empty() && input.starts_with(needle) {
             return Ok((input, ()));
-        } else if stack.last().map_or(false, |x| input.starts_with(x)) {
+        } else if stack.last().is_some_and(|x| input.starts_with(x)) {
             stack.pop();
         } else if input.starts_with("(") {
             stack.push(")");
@@ -335,7 +369,7 @@ fn test_cursor_advance() -> Result<(), LexError> {
     Ok(())
 }
 
-fn line_directive(path: &PathBuf, cur: Cursor) -> String {
+fn line_directive(path: &Path, cur: Cursor) -> String {
     let mut line = format!(
         "#line {} \"{}\"\n",
         cur.line + 1,
@@ -347,55 +381,265 @@ fn line_directive(path: &PathBuf, cur: Cursor) -> String {
     line
 }
 
+/// Compute the path given to `include!`. It can be a string literal, or be made with
+/// `concat!` and `env!` (e.g. `concat!(env!("OUT_DIR"), "/generated.rs")`), whose variables
+/// are looked up with `env`.
+fn include_path(x: Cursor, env: &dyn Fn(&str) -> Option<String>) -> Option<PathBuf> {
+    fn eval(expr: &syn::Expr, env: &dyn Fn(&str) -> Option<String>) -> Option<String> {
+        match expr {
+            syn::Expr::Lit(syn::ExprLit {
+                lit: syn::Lit::Str(s),
+                ..
+            }) => Some(s.value()),
+            syn::Expr::Group(g) => eval(&g.expr, env),
+            syn::Expr::Paren(p) => eval(&p.expr, env),
+            syn::Expr::Macro(m) => {
+                let args = m
+                    .mac
+                    .parse_body_with(Punctuated::<syn::Expr, Token![,]>::parse_terminated)
+                    .ok()?;
+                let name = m.mac.path.segments.last()?.ident.to_string();
+                match name.as_str() {
+                    "concat" => args.iter().map(|a| eval(a, env)).collect(),
+                    "env" if args.len() == 1 => env(&eval(&args[0], env)?),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+    let expr = syn::parse_str::<syn::Expr>(x.rest).ok()?;
+    eval(&expr, env).map(PathBuf::from)
+}
+
+#[test]
+fn test_include_path() {
+    let env = |name: &str| {
+        if name == "CPP_BUILD_TEST_INCLUDE_DIR" {
+            Some("/some/dir".to_owned())
+        } else {
+            None
+        }
+    };
+    assert_eq!(
+        include_path(new_cursor(r#""foo/bar.rs""#), &env),
+        Some(PathBuf::from("foo/bar.rs"))
+    );
+    assert_eq!(
+        include_path(
+            new_cursor(r#"concat!(env!("CPP_BUILD_TEST_INCLUDE_DIR"), "/gen", ".rs")"#),
+            &env
+        ),
+        Some(PathBuf::from("/some/dir/gen.rs"))
+    );
+    assert_eq!(
+        include_path(
+            new_cursor(r#"std::concat!(std::env!("CPP_BUILD_TEST_INCLUDE_DIR"), "/gen.rs",)"#),
+            &env
+        ),
+        Some(PathBuf::from("/some/dir/gen.rs"))
+    );
+    assert_eq!(
+        include_path(
+            new_cursor(r#"concat!(env!("CPP_BUILD_TEST_UNSET"), "/gen.rs")"#),
+            &env
+        ),
+        None
+    );
+    assert_eq!(include_path(new_cursor(r#"my_path!()"#), &env), None);
+}
+
+/// Convert a glob to a regular expression matching the path of a file, or of any
+/// file within the matched directory.
+fn glob_to_regex(glob: &str) -> Regex {
+    let mut re = String::from("^");
+    let mut chars = glob.trim_start_matches("./").chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    // `**/` matches any number of directories
+                    chars.next();
+                    re.push_str("(.*/)?");
+                } else {
+                    re.push_str(".*");
+                }
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            c => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    re.push_str("(/.*)?$");
+    Regex::new(&re).unwrap()
+}
+
+/// The path, relative to the package directory, with `/` as separator
+fn normalize_path(path: &Path) -> String {
+    let manifest_dir = env::var_os("CARGO_MANIFEST_DIR").unwrap_or_default();
+    path.strip_prefix(&manifest_dir)
+        .unwrap_or(path)
+        .components()
+        .filter(|c| *c != Component::CurDir)
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The files to skip, as configured with `Config::exclude` and `Config::only`
+#[derive(Default, Clone)]
+pub struct PathFilter {
+    exclude: Vec<Regex>,
+    only: Vec<Regex>,
+}
+
+impl PathFilter {
+    pub fn exclude(&mut self, glob: &str) {
+        self.exclude.push(glob_to_regex(glob));
+    }
+
+    pub fn only(&mut self, glob: &str) {
+        self.only.push(glob_to_regex(glob));
+    }
+
+    fn is_excluded(&self, path: &Path) -> bool {
+        let path = normalize_path(path);
+        self.exclude.iter().any(|re| re.is_match(&path))
+    }
+
+    fn is_only(&self, path: &Path) -> bool {
+        let path = normalize_path(path);
+        self.only.is_empty() || self.only.iter().any(|re| re.is_match(&path))
+    }
+}
+
+#[test]
+fn test_path_filter() {
+    let mut filter = PathFilter::default();
+    filter.exclude("src/vendor");
+    filter.exclude("./src/**/generated_*.rs");
+    assert!(!filter.is_excluded(Path::new("src/vendor.rs")));
+    assert!(filter.is_excluded(Path::new("src/vendor/mod.rs")));
+    assert!(filter.is_excluded(Path::new("./src/vendor/foo/bar.rs")));
+    assert!(filter.is_excluded(Path::new("src/generated_foo.rs")));
+    assert!(filter.is_excluded(Path::new("src/a/b/generated_foo.rs")));
+    assert!(!filter.is_excluded(Path::new("src/a/b/foo.rs")));
+    assert!(filter.is_only(Path::new("src/a/b/foo.rs")));
+
+    filter.only("src/ffi?.rs");
+    assert!(filter.is_only(Path::new("src/ffi1.rs")));
+    assert!(!filter.is_only(Path::new("src/ffi.rs")));
+    assert!(!filter.is_only(Path::new("src/ffi12.rs")));
+}
+
 #[derive(Default)]
 pub struct Parser {
     pub closures: Vec<Closure>,
     pub classes: Vec<Class>,
+    pub type_aliases: Vec<TypeAlias>,
+    pub impls: Vec<Impl>,
+    pub consts: Vec<Const>,
+    pub enums: Vec<Enum>,
+    pub structs: Vec<ExportedStruct>,
+    pub iters: Vec<Iter>,
     pub snippets: String,
     pub callbacks_count: u32,
     current_path: PathBuf, // The current file being parsed
     mod_dir: PathBuf,
     mod_error: Option<Error>, // An error occuring while visiting the modules
+    // The macro_rules! macros which expand to rust-cpp macros, by name
+    macro_rules: HashMap<String, mbe::MacroRules>,
+    collecting_macro_rules: bool, // true during the first pass
+    expansion_depth: u32,
+    pub filter: PathFilter,
+    /// The hashes of the cpp! and cpp_class! macros found in the excluded files
+    pub excluded_hashes: HashSet<u64>,
+    /// The signatures of the cpp! closures found in the excluded files, which are hashed
+    /// once their type aliases are resolved
+    pub excluded_closures: Vec<ClosureSig>,
+    /// The macro_rules! invocations which cannot be expanded
+    pub expansion_errors: Vec<String>,
+    excluded: bool,     // true if the current file is excluded
+    excluded_mod: bool, // true if the current module or a parent matches an exclude glob
 }
 
+// Same as rustc's default recursion_limit
+const MAX_EXPANSION_DEPTH: u32 = 128;
+
 impl Parser {
     pub fn parse_crate<P: AsRef<Path>>(&mut self, crate_root: P) -> Result<(), Error> {
+        // The macro_rules! definitions are collected in a first pass, so they can be
+        // expanded even when they are invoked before the module defining them is parsed.
+        self.collecting_macro_rules = true;
+        self.parse_mod(&crate_root)?;
+        self.collecting_macro_rules = false;
         self.parse_mod(crate_root)
     }
 
     fn parse_mod<P: AsRef<Path>>(&mut self, mod_path: P) -> Result<(), Error> {
+        // Excluded files may contain anything: they are only looked at, when possible, to
+        // give a clear error to their rust-cpp macros, and to visit their modules.
+        let (mut excluded, mut excluded_mod) = self.check_excluded(mod_path.as_ref());
+
         let mut s = String::new();
-        let mut f = File::open(&mod_path).map_err(|_| Error::ParseCannotOpenFile {
-            src_path: mod_path.as_ref().to_str().unwrap().to_owned(),
-        })?;
-        f.read_to_string(&mut s)
-            .map_err(|_| Error::ParseCannotOpenFile {
+        if File::open(&mod_path)
+            .and_then(|mut f| f.read_to_string(&mut s))
+            .is_err()
+        {
+            if excluded {
+                return Ok(());
+            }
+            return Err(Error::ParseCannotOpenFile {
                 src_path: mod_path.as_ref().to_str().unwrap().to_owned(),
-            })?;
+            });
+        }
 
-        let fi = syn::parse_file(&s).map_err(|x| Error::ParseSyntaxError {
-            src_path: mod_path.as_ref().to_str().unwrap().to_owned(),
-            error: x,
-        })?;
+        let fi = match syn::parse_file(&s) {
+            Ok(fi) => Some(fi),
+            Err(_) if excluded => None,
+            Err(x) => {
+                return Err(Error::ParseSyntaxError {
+                    src_path: mod_path.as_ref().to_str().unwrap().to_owned(),
+                    error: x,
+                })
+            }
+        };
 
         let mut current_path = mod_path.as_ref().into();
         let mut mod_dir = mod_path.as_ref().parent().unwrap().into();
 
         swap(&mut self.current_path, &mut current_path);
         swap(&mut self.mod_dir, &mut mod_dir);
+        swap(&mut self.excluded, &mut excluded);
+        swap(&mut self.excluded_mod, &mut excluded_mod);
 
-        self.find_cpp_macros(&s)?;
-        self.visit_file(&fi);
+        let result = self.find_cpp_macros(&s);
+        if !self.excluded {
+            result?;
+        }
+        if let Some(fi) = fi {
+            self.visit_file(&fi);
+        }
         if let Some(err) = self.mod_error.take() {
             return Err(err);
         }
 
         swap(&mut self.current_path, &mut current_path);
         swap(&mut self.mod_dir, &mut mod_dir);
+        swap(&mut self.excluded, &mut excluded);
+        swap(&mut self.excluded_mod, &mut excluded_mod);
 
         Ok(())
     }
 
+    /// Returns whether the file is excluded, and whether it is excluded by
+    /// an exclude glob (which also applies to its sub-modules).
+    fn check_excluded(&self, path: &Path) -> (bool, bool) {
+        let excluded_mod = self.excluded_mod || self.filter.is_excluded(path);
+        (excluded_mod || !self.filter.is_only(path), excluded_mod)
+    }
+
     /*
     fn parse_macro(&mut self, tts: TokenStream) {
         let mut last_ident: Option<syn::Ident> = None;
@@ -428,7 +672,15 @@ impl Parser {
     */
 
     fn find_cpp_macros(&mut self, source: &str) -> Result<(), Error> {
-        let mut cursor = new_cursor(source);
+        let result = self.find_cpp_macros_at(new_cursor(source));
+        if self.excluded {
+            // Errors in excluded files are ignored
+            return Ok(());
+        }
+        result
+    }
+
+    fn find_cpp_macros_at(&mut self, mut cursor: Cursor) -> Result<(), Error> {
         while !cursor.is_empty() {
             cursor = skip_whitespace(cursor);
             let r = skip_literal(cursor).map_err(|e| self.lex_error(e))?;
@@ -438,7 +690,18 @@ impl Parser {
             }
             if let Ok((cur, ident)) = symbol(cursor) {
                 cursor = cur;
-                if ident != "cpp" && ident != "cpp_class" {
+                if ident != "cpp"
+                    && ident != "cpp_class"
+                    && ident != "cpp_type"
+                    && ident != "cpp_impl"
+                    && ident != "cpp_const"
+                    && ident != "cpp_enum"
+                    && ident != "cpp_export_struct"
+                    && ident != "cpp_iter"
+                    && ident != "macro_rules"
+                    && ident != "include"
+                    && !self.macro_rules.contains_key(ident)
+                {
                     continue;
                 }
                 cursor = skip_whitespace(cursor);
@@ -446,6 +709,16 @@ impl Parser {
                     continue;
                 }
                 cursor = skip_whitespace(cursor.advance(1));
+                let mut rules_name = "";
+                if ident == "macro_rules" {
+                    match symbol(cursor) {
+                        Ok((cur, name)) => {
+                            rules_name = name;
+                            cursor = skip_whitespace(cur);
+                        }
+                        Err(_) => continue,
+                    }
+                }
                 let delim = if cursor.starts_with("(") {
                     ")"
                 } else if cursor.starts_with("[") {
@@ -462,21 +735,81 @@ impl Parser {
                     .0;
                 let size = (cursor.off - macro_cur.off) as usize;
                 macro_cur.rest = &macro_cur.rest[..size];
-                if ident == "cpp" {
+                if ident == "macro_rules" {
+                    // The body of the definition is only parsed when it is expanded
+                    if self.collecting_macro_rules && !self.excluded {
+                        let result = self.handle_macro_rules(rules_name, macro_cur);
+                        self.expansion_error(result);
+                    }
+                } else if self.excluded {
+                    if !self.collecting_macro_rules {
+                        self.record_excluded(ident, macro_cur);
+                    }
+                } else if ident == "include" {
+                    // Followed in both passes, as the included file may define macro_rules!
+                    self.handle_include(macro_cur)?;
+                } else if self.collecting_macro_rules {
+                    // Only the macro_rules! definitions are looked at during the first pass
+                } else if ident == "cpp" {
                     self.handle_cpp(macro_cur).unwrap_or_else(|e| {
                         panic!(
                             "Error while parsing cpp! macro:\n{:?}:{}",
                             self.current_path, e
                         )
                     });
-                } else {
-                    debug_assert_eq!(ident, "cpp_class");
+                } else if ident == "cpp_class" {
                     self.handle_cpp_class(macro_cur).unwrap_or_else(|e| {
                         panic!(
                             "Error while parsing cpp_class! macro:\n{:?}:{}",
                             self.current_path, e
                         )
                     });
+                } else if ident == "cpp_type" {
+                    self.handle_cpp_type(macro_cur).unwrap_or_else(|e| {
+                        panic!(
+                            "Error while parsing cpp_type! macro:\n{:?}:{}",
+                            self.current_path, e
+                        )
+                    });
+                } else if ident == "cpp_impl" {
+                    self.handle_cpp_impl(macro_cur).unwrap_or_else(|e| {
+                        panic!(
+                            "Error while parsing cpp_impl! macro:\n{:?}:{}",
+                            self.current_path, e
+                        )
+                    });
+                } else if ident == "cpp_const" {
+                    self.handle_cpp_const(macro_cur).unwrap_or_else(|e| {
+                        panic!(
+                            "Error while parsing cpp_const! macro:\n{:?}:{}",
+                            self.current_path, e
+                        )
+                    });
+                } else if ident == "cpp_enum" {
+                    self.handle_cpp_enum(macro_cur).unwrap_or_else(|e| {
+                        panic!(
+                            "Error while parsing cpp_enum! macro:\n{:?}:{}",
+                            self.current_path, e
+                        )
+                    });
+                } else if ident == "cpp_export_struct" {
+                    self.handle_cpp_export_struct(macro_cur)
+                        .unwrap_or_else(|e| {
+                            panic!(
+                                "Error while parsing cpp_export_struct! macro:\n{:?}:{}",
+                                self.current_path, e
+                            )
+                        });
+                } else if ident == "cpp_iter" {
+                    self.handle_cpp_iter(macro_cur).unwrap_or_else(|e| {
+                        panic!(
+                            "Error while parsing cpp_iter! macro:\n{:?}:{}",
+                            self.current_path, e
+                        )
+                    });
+                } else {
+                    let result = self.expand_macro_rules(ident, macro_cur);
+                    self.expansion_error(result);
                 }
                 continue;
             }
@@ -488,6 +821,21 @@ impl Parser {
         Ok(())
     }
 
+    /// A macro_rules! macro which cannot be expanded only loses the rust-cpp macros it
+    /// generates: the other macros of the crate are still built, and rustc reports invalid
+    /// invocations. The error is recorded, so that the macros which are not found name it.
+    fn expansion_error(&mut self, result: Result<(), Error>) {
+        if let Err(err) = result {
+            warnln!(
+                r#"-- rust-cpp macro expansion error --
+{}
+The rust-cpp macros generated by this invocation are ignored."#,
+                err
+            );
+            self.expansion_errors.push(err.to_string());
+        }
+    }
+
     fn lex_error(&self, e: LexError) -> Error {
         Error::LexError {
             src_path: self.current_path.clone().to_str().unwrap().to_owned(),
@@ -495,6 +843,175 @@ impl Parser {
         }
     }
 
+    fn macro_expansion_error(&self, name: &str, e: LineError) -> Error {
+        Error::MacroExpansionError {
+            src_path: self.current_path.clone().to_str().unwrap().to_owned(),
+            line: e.0,
+            name: name.to_owned(),
+            error: e.1,
+        }
+    }
+
+    fn handle_macro_rules(&mut self, name: &str, x: Cursor) -> Result<(), Error> {
+        let is_relevant = |i: &str| {
+            i == "cpp"
+                || i == "cpp_class"
+                || i == "cpp_type"
+                || i == "cpp_impl"
+                || i == "cpp_const"
+                || i == "cpp_enum"
+                || i == "cpp_export_struct"
+                || i == "cpp_iter"
+                || self.macro_rules.contains_key(i)
+        };
+        let rules = mbe::MacroRules::parse(x, self.current_path.clone(), &is_relevant)
+            .map_err(|e| self.macro_expansion_error("macro_rules", e))?;
+        if let Some(rules) = rules {
+            self.macro_rules.insert(name.to_owned(), rules);
+        }
+        Ok(())
+    }
+
+    /// Expand the invocation of a macro_rules! macro, and look for the rust-cpp macros
+    /// in the expansion. (The expansion may contain further macro_rules! invocations.)
+    fn expand_macro_rules(&mut self, name: &str, x: Cursor) -> Result<(), Error> {
+        if self.expansion_depth >= MAX_EXPANSION_DEPTH {
+            return Err(self
+                .macro_expansion_error(name, LineError(x.line, "recursion limit reached".into())));
+        }
+        let (expansion, mut path) = {
+            let rules = &self.macro_rules[name];
+            (rules.expand(x), rules.path.clone())
+        };
+        let expansion = expansion.map_err(|e| self.macro_expansion_error(name, e))?;
+
+        // Errors within the expansion point to the macro definition
+        swap(&mut self.current_path, &mut path);
+        self.expansion_depth += 1;
+        let result = self.find_cpp_macros_at(Cursor {
+            rest: &expansion.text,
+            off: 0,
+            line: expansion.line,
+            column: expansion.column,
+        });
+        self.expansion_depth -= 1;
+        swap(&mut self.current_path, &mut path);
+        result
+    }
+
+    /// Parse the file included by `include!(...)` as if its content was at the location
+    /// of the invocation. Invocations whose path cannot be computed, or whose file cannot
+    /// be read, are ignored: rustc reports the missing files itself.
+    fn handle_include(&mut self, x: Cursor) -> Result<(), Error> {
+        // OUT_DIR and CARGO_MANIFEST_DIR are the same for the build script
+        let path = match include_path(x, &|name| env::var(name).ok()) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if self.expansion_depth >= MAX_EXPANSION_DEPTH {
+            return Err(self.macro_expansion_error(
+                "include",
+                LineError(x.line, "recursion limit reached".into()),
+            ));
+        }
+        // Relative paths are relative to the file containing the invocation
+        let path = self.current_path.parent().unwrap().join(path);
+
+        let (mut excluded, mut excluded_mod) = self.check_excluded(&path);
+        let mut s = String::new();
+        if File::open(&path)
+            .and_then(|mut f| f.read_to_string(&mut s))
+            .is_err()
+        {
+            if excluded || self.collecting_macro_rules {
+                return Ok(());
+            }
+            warnln!(
+                "-- rust-cpp parse warning --\n{}:{}: Cannot read the file `{}` included by \
+                 `include!`, its rust-cpp macros are ignored.",
+                self.current_path.display(),
+                x.line + 1,
+                path.display()
+            );
+            return Ok(());
+        }
+
+        let mut current_path = path;
+        swap(&mut self.current_path, &mut current_path);
+        swap(&mut self.excluded, &mut excluded);
+        swap(&mut self.excluded_mod, &mut excluded_mod);
+        self.expansion_depth += 1;
+        let mut result = self.find_cpp_macros(&s);
+        if self.excluded {
+            // Excluded files may contain anything
+            result = Ok(());
+        }
+        // The included file may be an expression rather than items, in which case it
+        // cannot contain any module declaration.
+        if result.is_ok() {
+            if let Ok(fi) = syn::parse_file(&s) {
+                self.visit_file(&fi);
+                if let Some(err) = self.mod_error.take() {
+                    result = Err(err);
+                }
+            }
+        }
+        self.expansion_depth -= 1;
+        swap(&mut self.current_path, &mut current_path);
+        swap(&mut self.excluded, &mut excluded);
+        swap(&mut self.excluded_mod, &mut excluded_mod);
+        result
+    }
+
+    /// Replace the `cpp_type!` aliases of the closures by their C++ types, which their
+    /// hashes depend on
+    pub fn resolve_type_aliases(&mut self, aliases: &HashMap<String, String>) {
+        for c in &mut self.closures {
+            c.sig.resolve_type_aliases(aliases);
+        }
+        for sig in &mut self.excluded_closures {
+            sig.resolve_type_aliases(aliases);
+        }
+    }
+
+    /// Remember the hash of a rust-cpp macro in an excluded file, so that its
+    /// expansion can report a better error.
+    fn record_excluded(&mut self, ident: &str, x: Cursor) {
+        let input = match x.rest.parse::<::proc_macro2::TokenStream>() {
+            Ok(input) => input,
+            Err(_) => return,
+        };
+        if ident == "cpp" {
+            if let Ok(Macro::Closure(c)) = ::syn::parse2::<Macro>(input) {
+                self.excluded_closures.push(c.sig);
+            }
+        } else if ident == "cpp_class" {
+            if let Ok(class) = ::syn::parse2::<Class>(input) {
+                self.excluded_hashes.insert(class.name_hash());
+            }
+        } else if ident == "cpp_impl" {
+            if let Ok(imp) = ::syn::parse2::<Impl>(input) {
+                self.excluded_hashes.insert(imp.name_hash());
+            }
+        } else if ident == "cpp_const" {
+            if let Ok(c) = ::syn::parse2::<Const>(input) {
+                self.excluded_hashes.insert(c.name_hash());
+            }
+        } else if ident == "cpp_enum" {
+            if let Ok(e) = ::syn::parse2::<Enum>(input) {
+                self.excluded_hashes.insert(e.name_hash());
+            }
+        } else if ident == "cpp_export_struct" {
+            if let Ok(s) = ::syn::parse2::<ExportedStruct>(input) {
+                self.excluded_hashes.insert(s.name_hash());
+            }
+        } else if ident == "cpp_iter" {
+            if let Ok(it) = ::syn::parse2::<Iter>(input) {
+                self.excluded_hashes.insert(it.name_hash());
+            }
+        }
+    }
+
     fn handle_cpp(&mut self, x: Cursor) -> Result<(), LineError> {
         // Since syn don't give the exact string, we extract manually
         let begin = (find_delimited(x, "{")?.0).advance(1);
@@ -540,6 +1057,73 @@ impl Parser {
         self.classes.push(class);
         Ok(())
     }
+
+    fn handle_cpp_impl(&mut self, x: Cursor) -> Result<(), LineError> {
+        let input: ::proc_macro2::TokenStream = x
+            .rest
+            .parse()
+            .map_err(|_| LineError(x.line, "TokenStream parse error".into()))?;
+        let mut imp = ::syn::parse2::<Impl>(input).map_err(|e| LineError(x.line, e.to_string()))?;
+        imp.line = line_directive(&self.current_path, x);
+        self.impls.push(imp);
+        Ok(())
+    }
+
+    fn handle_cpp_const(&mut self, x: Cursor) -> Result<(), LineError> {
+        let input: ::proc_macro2::TokenStream = x
+            .rest
+            .parse()
+            .map_err(|_| LineError(x.line, "TokenStream parse error".into()))?;
+        let mut c = ::syn::parse2::<Const>(input).map_err(|e| LineError(x.line, e.to_string()))?;
+        c.line = line_directive(&self.current_path, x);
+        self.consts.push(c);
+        Ok(())
+    }
+
+    fn handle_cpp_enum(&mut self, x: Cursor) -> Result<(), LineError> {
+        let input: ::proc_macro2::TokenStream = x
+            .rest
+            .parse()
+            .map_err(|_| LineError(x.line, "TokenStream parse error".into()))?;
+        let mut e = ::syn::parse2::<Enum>(input).map_err(|e| LineError(x.line, e.to_string()))?;
+        e.line = line_directive(&self.current_path, x);
+        self.enums.push(e);
+        Ok(())
+    }
+
+    fn handle_cpp_export_struct(&mut self, x: Cursor) -> Result<(), LineError> {
+        let input: ::proc_macro2::TokenStream = x
+            .rest
+            .parse()
+            .map_err(|_| LineError(x.line, "TokenStream parse error".into()))?;
+        let mut s =
+            ::syn::parse2::<ExportedStruct>(input).map_err(|e| LineError(x.line, e.to_string()))?;
+        s.line = line_directive(&self.current_path, x);
+        self.structs.push(s);
+        Ok(())
+    }
+
+    fn handle_cpp_iter(&mut self, x: Cursor) -> Result<(), LineError> {
+        let input: ::proc_macro2::TokenStream = x
+            .rest
+            .parse()
+            .map_err(|_| LineError(x.line, "TokenStream parse error".into()))?;
+        let mut it = ::syn::parse2::<Iter>(input).map_err(|e| LineError(x.line, e.to_string()))?;
+        it.line = line_directive(&self.current_path, x);
+        self.iters.push(it);
+        Ok(())
+    }
+
+    fn handle_cpp_type(&mut self, x: Cursor) -> Result<(), LineError> {
+        let input: ::proc_macro2::TokenStream = x
+            .rest
+            .parse()
+            .map_err(|_| LineError(x.line, "TokenStream parse error".into()))?;
+        let alias =
+            ::syn::parse2::<TypeAlias>(input).map_err(|e| LineError(x.line, e.to_string()))?;
+        self.type_aliases.push(alias);
+        Ok(())
+    }
 }
 
 impl<'ast> Visit<'ast> for Parser {
@@ -580,7 +1164,7 @@ impl<'ast> Visit<'ast> for Parser {
                     lit: syn::Lit::Str(ref s),
                     ..
                 })) if path.is_ident("path") => {
-                    let mod_path = self.mod_dir.join(&s.value());
+                    let mod_path = self.mod_dir.join(s.value());
                     return self
                         .parse_mod(mod_path)
                         .unwrap_or_else(|err| self.mod_error = Some(err));
@@ -633,7 +1217,7 @@ impl<'ast> Visit<'ast> for Parser {
             }
         }
 
-        let adjacent = self.mod_dir.join(&format!("{}.rs", mod_name));
+        let adjacent = self.mod_dir.join(format!("{}.rs", mod_name));
         if adjacent.is_file() {
             return self
                 .parse_mod(adjacent)
@@ -0,0 +1,701 @@
+//! A minimal "macro by example" (`macro_rules!`) expander.
+//!
+//! It is used to find the `cpp!` and `cpp_class!` macros which are generated by
+//! the crate's own `macro_rules!` macros. The expansion is purely textual: the
+//! transcriber is copied verbatim (so the C++ code keeps its formatting and line
+//! breaks) and each metavariable is replaced by the source text it matched.
+
+use super::{skip_literal, LineError};
+use crate::strnom::*;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+enum TokenKind<'a> {
+    Ident,
+    Literal,
+    Lifetime,
+    Punct,
+    Group(char, Vec<Token<'a>>),
+}
+
+#[derive(Debug)]
+struct Token<'a> {
+    kind: TokenKind<'a>,
+    text: &'a str,
+    start: usize, // offset of the token, as given by `Cursor::off`
+    line: u32,
+    column: u32,
+}
+
+impl<'a> Token<'a> {
+    fn end(&self) -> usize {
+        self.start + self.text.len()
+    }
+
+    fn is_punct(&self, c: &str) -> bool {
+        match self.kind {
+            TokenKind::Punct => self.text == c,
+            _ => false,
+        }
+    }
+}
+
+fn tokenize(input: Cursor) -> Result<Vec<Token>, LexError> {
+    Ok(tokenize_until(input, None)?.1)
+}
+
+fn tokenize_until(mut input: Cursor, close: Option<char>) -> PResult<Vec<Token>> {
+    let mut tokens = Vec::new();
+    loop {
+        input = skip_whitespace(input);
+        let c = match input.chars().next() {
+            Some(c) => c,
+            None if close.is_none() => return Ok((input, tokens)),
+            None => return Err(LexError { line: input.line }),
+        };
+        if Some(c) == close {
+            return Ok((input, tokens));
+        }
+        let begin = input;
+        let (rest, is_literal) = skip_literal(input)?;
+        let (rest, kind) = if is_literal {
+            let text = &begin.rest[..(rest.off - begin.off) as usize];
+            if text.starts_with('\'') && !text.ends_with('\'') {
+                (rest, TokenKind::Lifetime)
+            } else {
+                (rest, TokenKind::Literal)
+            }
+        } else if c.is_ascii_digit() {
+            let mut len = 0;
+            let mut chars = input.rest.chars().peekable();
+            while let Some(ch) = chars.next() {
+                let continues = ch.is_alphanumeric()
+                    || ch == '_'
+                    || (ch == '.' && chars.peek().is_some_and(|x| x.is_ascii_digit()));
+                if !continues {
+                    break;
+                }
+                len += ch.len_utf8();
+            }
+            (input.advance(len), TokenKind::Literal)
+        } else if let Ok((rest, _)) = symbol(input) {
+            (rest, TokenKind::Ident)
+        } else if c == '(' || c == '[' || c == '{' {
+            let close = match c {
+                '(' => ')',
+                '[' => ']',
+                _ => '}',
+            };
+            let (rest, inner) = tokenize_until(input.advance(1), Some(close))?;
+            (rest.advance(1), TokenKind::Group(c, inner))
+        } else if c == ')' || c == ']' || c == '}' {
+            return Err(LexError { line: input.line });
+        } else {
+            (input.advance(c.len_utf8()), TokenKind::Punct)
+        };
+        tokens.push(Token {
+            kind,
+            text: &begin.rest[..(rest.off - begin.off) as usize],
+            start: begin.off as usize,
+            line: begin.line,
+            column: begin.column,
+        });
+        input = rest;
+    }
+}
+
+/// Returns true if any of the tokens is an invocation of a macro for which `f` returns true
+fn invokes_macro(tokens: &[Token], f: &dyn Fn(&str) -> bool) -> bool {
+    tokens.iter().enumerate().any(|(i, t)| match t.kind {
+        TokenKind::Ident => f(t.text) && tokens.get(i + 1).is_some_and(|t| t.is_punct("!")),
+        TokenKind::Group(_, ref inner) => invokes_macro(inner, f),
+        _ => false,
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Fragment {
+    TokenTree,
+    Ident,
+    Literal,
+    Lifetime,
+    Block,
+    Vis,
+    Expr,
+    Other, // ty, path, pat, ...: anything up to the next `;` or `=>`
+}
+
+impl Fragment {
+    fn from_name(name: &str) -> Option<Fragment> {
+        Some(match name {
+            "tt" => Fragment::TokenTree,
+            "ident" => Fragment::Ident,
+            "literal" => Fragment::Literal,
+            "lifetime" => Fragment::Lifetime,
+            "block" => Fragment::Block,
+            "vis" => Fragment::Vis,
+            "expr" | "expr_2021" => Fragment::Expr,
+            "ty" | "path" | "pat" | "pat_param" | "stmt" | "item" | "meta" => Fragment::Other,
+            _ => return None,
+        })
+    }
+
+    /// The possible number of tokens this fragment can match, in order of preference
+    fn candidate_lengths(self, tokens: &[Token]) -> Vec<usize> {
+        let first = match tokens.first() {
+            Some(first) => first,
+            None if self == Fragment::Vis => return vec![0],
+            None => return vec![],
+        };
+        match (self, &first.kind) {
+            (Fragment::TokenTree, _)
+            | (Fragment::Ident, TokenKind::Ident)
+            | (Fragment::Literal, TokenKind::Literal)
+            | (Fragment::Lifetime, TokenKind::Lifetime)
+            | (Fragment::Block, TokenKind::Group('{', _)) => vec![1],
+            (Fragment::Literal, TokenKind::Punct) if first.text == "-" => match tokens.get(1) {
+                Some(Token {
+                    kind: TokenKind::Literal,
+                    ..
+                }) => vec![2],
+                _ => vec![],
+            },
+            (Fragment::Vis, TokenKind::Ident) if first.text == "pub" => match tokens.get(1) {
+                Some(Token {
+                    kind: TokenKind::Group('(', _),
+                    ..
+                }) => vec![2, 1],
+                _ => vec![1],
+            },
+            (Fragment::Vis, _) => vec![0],
+            (Fragment::Expr, _) | (Fragment::Other, _) => {
+                let ends = |i: usize| {
+                    let t = &tokens[i];
+                    t.is_punct(";")
+                        || (t.is_punct("=") && tokens.get(i + 1).is_some_and(|t| t.is_punct(">")))
+                        || (self == Fragment::Expr && t.is_punct(","))
+                };
+                let len = (0..tokens.len()).find(|&i| ends(i)).unwrap_or(tokens.len());
+                // The shortest fragment is tried first, since the following matchers decide
+                // where it ends. Types and paths must also have balanced angle brackets.
+                let mut depth = 0;
+                let mut result = Vec::new();
+                for i in 0..len {
+                    if tokens[i].is_punct("<") {
+                        depth += 1;
+                    } else if tokens[i].is_punct(">") && (i == 0 || !tokens[i - 1].is_punct("-")) {
+                        depth -= 1;
+                    }
+                    if self == Fragment::Expr || depth == 0 {
+                        result.push(i + 1);
+                    }
+                }
+                result
+            }
+            _ => vec![],
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Repetition<T> {
+    sub: Vec<T>,
+    separator: Option<String>,
+    op: char,
+}
+
+#[derive(Debug)]
+enum Matcher {
+    Token(String),
+    Group(char, Vec<Matcher>),
+    Var(String, Fragment),
+    Repeat(Repetition<Matcher>),
+}
+
+#[derive(Debug)]
+enum Transcriber {
+    Text(String),
+    Var(String),
+    Repeat(Repetition<Transcriber>),
+}
+
+#[derive(Clone, Debug)]
+enum Binding {
+    Text(String),
+    Repeated(Vec<Binding>),
+}
+
+type Bindings = HashMap<String, Binding>;
+
+/// Parse the `sep? op` following a `$(...)` repetition. `i` points after the parentheses.
+fn parse_repetition_op(
+    tokens: &[Token],
+    i: &mut usize,
+) -> Result<(Option<String>, char), LineError> {
+    let is_op = |t: &Token| t.is_punct("*") || t.is_punct("+") || t.is_punct("?");
+    let line = tokens[*i - 1].line;
+    match (tokens.get(*i), tokens.get(*i + 1)) {
+        (Some(op), _) if is_op(op) => {
+            *i += 1;
+            Ok((None, op.text.chars().next().unwrap()))
+        }
+        (Some(sep), Some(op)) if is_op(op) => {
+            *i += 2;
+            Ok((Some(sep.text.to_owned()), op.text.chars().next().unwrap()))
+        }
+        _ => Err(LineError(
+            line,
+            "expected one of: `*`, `+`, or `?` after a repetition".into(),
+        )),
+    }
+}
+
+fn parse_matcher(tokens: &[Token]) -> Result<Vec<Matcher>, LineError> {
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        let t = &tokens[i];
+        i += 1;
+        match t.kind {
+            TokenKind::Punct if t.text == "$" => match tokens.get(i) {
+                Some(Token {
+                    kind: TokenKind::Ident,
+                    text: name,
+                    ..
+                }) => {
+                    let fragment = match (tokens.get(i + 1), tokens.get(i + 2)) {
+                        (Some(colon), Some(frag)) if colon.is_punct(":") => {
+                            Fragment::from_name(frag.text).ok_or_else(|| {
+                                LineError(
+                                    frag.line,
+                                    format!("unsupported fragment specifier `{}`", frag.text),
+                                )
+                            })?
+                        }
+                        _ => {
+                            return Err(LineError(
+                                t.line,
+                                format!("missing fragment specifier for `${}`", name),
+                            ))
+                        }
+                    };
+                    i += 3;
+                    result.push(Matcher::Var((*name).to_owned(), fragment));
+                }
+                Some(Token {
+                    kind: TokenKind::Group('(', ref inner),
+                    ..
+                }) => {
+                    i += 1;
+                    let sub = parse_matcher(inner)?;
+                    let (separator, op) = parse_repetition_op(tokens, &mut i)?;
+                    result.push(Matcher::Repeat(Repetition { sub, separator, op }));
+                }
+                _ => result.push(Matcher::Token(t.text.to_owned())),
+            },
+            TokenKind::Group(delim, ref inner) => {
+                result.push(Matcher::Group(delim, parse_matcher(inner)?))
+            }
+            _ => result.push(Matcher::Token(t.text.to_owned())),
+        }
+    }
+    Ok(result)
+}
+
+/// Parse a transcriber, keeping the text between the metavariables verbatim.
+/// `pos` is the offset up to which the text was already consumed.
+fn parse_transcriber(
+    src: &str,
+    tokens: &[Token],
+    pos: &mut usize,
+    end: usize,
+    result: &mut Vec<Transcriber>,
+) -> Result<(), LineError> {
+    let mut i = 0;
+    while i < tokens.len() {
+        let t = &tokens[i];
+        match (&t.kind, tokens.get(i + 1)) {
+            (TokenKind::Punct, Some(next)) if t.text == "$" => match next.kind {
+                TokenKind::Ident => {
+                    result.push(Transcriber::Text(src[*pos..t.start].to_owned()));
+                    result.push(if next.text == "crate" {
+                        Transcriber::Text("crate".to_owned())
+                    } else {
+                        Transcriber::Var(next.text.to_owned())
+                    });
+                    *pos = next.end();
+                    i += 2;
+                }
+                TokenKind::Group('(', ref inner) => {
+                    result.push(Transcriber::Text(src[*pos..t.start].to_owned()));
+                    let mut sub = Vec::new();
+                    let mut sub_pos = next.start + 1;
+                    parse_transcriber(src, inner, &mut sub_pos, next.end() - 1, &mut sub)?;
+                    i += 2;
+                    let (separator, op) = parse_repetition_op(tokens, &mut i)?;
+                    result.push(Transcriber::Repeat(Repetition { sub, separator, op }));
+                    *pos = tokens[i - 1].end();
+                }
+                _ => i += 1,
+            },
+            (TokenKind::Group(_, ref inner), _) => {
+                parse_transcriber(src, inner, pos, t.end() - 1, result)?;
+                i += 1;
+            }
+            _ => i += 1,
+        }
+    }
+    result.push(Transcriber::Text(src[*pos..end].to_owned()));
+    *pos = end;
+    Ok(())
+}
+
+type Continuation<'c, 't, 'a> = &'c mut dyn FnMut(&'t [Token<'a>]) -> Option<Bindings>;
+
+/// Match the `tokens` against the `matchers`, calling `k` with the remaining tokens.
+/// This is a backtracking matcher: `k` returning `None` makes us try another alternative.
+fn match_seq<'t, 'a>(
+    src: &Cursor,
+    matchers: &[Matcher],
+    tokens: &'t [Token<'a>],
+    k: Continuation<'_, 't, 'a>,
+) -> Option<Bindings> {
+    let (m, rest) = match matchers.split_first() {
+        Some(x) => x,
+        None => return k(tokens),
+    };
+    match *m {
+        Matcher::Token(ref text) => match tokens.first() {
+            Some(t) if t.text == text => match_seq(src, rest, &tokens[1..], k),
+            _ => None,
+        },
+        Matcher::Group(delim, ref sub) => match tokens.first() {
+            Some(Token {
+                kind: TokenKind::Group(d, ref inner),
+                ..
+            }) if *d == delim => {
+                let mut bindings = match_seq(src, sub, inner, &mut |r| {
+                    if r.is_empty() {
+                        Some(Bindings::new())
+                    } else {
+                        None
+                    }
+                })?;
+                bindings.extend(match_seq(src, rest, &tokens[1..], k)?);
+                Some(bindings)
+            }
+            _ => None,
+        },
+        Matcher::Var(ref name, fragment) => {
+            for len in fragment.candidate_lengths(tokens) {
+                if let Some(mut bindings) = match_seq(src, rest, &tokens[len..], k) {
+                    let text = if len == 0 {
+                        ""
+                    } else {
+                        let base = src.off as usize;
+                        &src.rest[tokens[0].start - base..tokens[len - 1].end() - base]
+                    };
+                    bindings.insert(name.clone(), Binding::Text(text.to_owned()));
+                    return Some(bindings);
+                }
+            }
+            None
+        }
+        Matcher::Repeat(ref rep) => {
+            let (iterations, mut bindings) = match_repeat(src, rep, rest, tokens, 0, k)?;
+            let mut names = Vec::new();
+            matcher_vars(&rep.sub, &mut names);
+            for name in names {
+                let seq = iterations
+                    .iter()
+                    .filter_map(|b| b.get(&name).cloned())
+                    .collect();
+                bindings.insert(name, Binding::Repeated(seq));
+            }
+            Some(bindings)
+        }
+    }
+}
+
+/// Match as many iterations of the repetition as possible, then the `rest`.
+/// Returns the bindings of each iteration, and the bindings of the rest.
+fn match_repeat<'t, 'a>(
+    src: &Cursor,
+    rep: &Repetition<Matcher>,
+    rest: &[Matcher],
+    tokens: &'t [Token<'a>],
+    count: usize,
+    k: Continuation<'_, 't, 'a>,
+) -> Option<(Vec<Bindings>, Bindings)> {
+    if rep.op != '?' || count == 0 {
+        let after_sep = match rep.separator {
+            Some(ref sep) if count > 0 => match tokens.first() {
+                Some(t) if t.text == sep => Some(&tokens[1..]),
+                _ => None,
+            },
+            _ => Some(tokens),
+        };
+        if let Some(after_sep) = after_sep {
+            let mut tail = None;
+            let iteration = match_seq(src, &rep.sub, after_sep, &mut |r| {
+                if r.len() == after_sep.len() {
+                    return None; // no progress
+                }
+                tail = Some(match_repeat(src, rep, rest, r, count + 1, k)?);
+                Some(Bindings::new())
+            });
+            if let (Some(iteration), Some((mut iterations, bindings))) = (iteration, tail) {
+                iterations.insert(0, iteration);
+                return Some((iterations, bindings));
+            }
+        }
+    }
+    if rep.op == '+' && count == 0 {
+        return None;
+    }
+    Some((Vec::new(), match_seq(src, rest, tokens, k)?))
+}
+
+fn matcher_vars(matchers: &[Matcher], names: &mut Vec<String>) {
+    for m in matchers {
+        match *m {
+            Matcher::Var(ref name, _) => names.push(name.clone()),
+            Matcher::Group(_, ref sub) => matcher_vars(sub, names),
+            Matcher::Repeat(ref rep) => matcher_vars(&rep.sub, names),
+            Matcher::Token(_) => {}
+        }
+    }
+}
+
+fn transcriber_vars(transcribers: &[Transcriber], names: &mut Vec<String>) {
+    for t in transcribers {
+        match *t {
+            Transcriber::Var(ref name) => names.push(name.clone()),
+            Transcriber::Repeat(ref rep) => transcriber_vars(&rep.sub, names),
+            Transcriber::Text(_) => {}
+        }
+    }
+}
+
+fn lookup<'b>(bindings: &'b Bindings, name: &str, indices: &[usize]) -> Option<&'b Binding> {
+    let mut binding = bindings.get(name)?;
+    for &i in indices {
+        match *binding {
+            Binding::Repeated(ref seq) => binding = &seq[i],
+            Binding::Text(_) => break,
+        }
+    }
+    Some(binding)
+}
+
+fn transcribe(
+    transcribers: &[Transcriber],
+    bindings: &Bindings,
+    indices: &mut Vec<usize>,
+    result: &mut String,
+) -> Result<(), String> {
+    for t in transcribers {
+        match *t {
+            Transcriber::Text(ref text) => result.push_str(text),
+            Transcriber::Var(ref name) => match lookup(bindings, name, indices) {
+                Some(Binding::Text(text)) => result.push_str(text),
+                Some(Binding::Repeated(_)) => {
+                    return Err(format!(
+                        "variable `{}` is still repeating at this depth",
+                        name
+                    ))
+                }
+                // Not one of our metavariables (for example in a nested macro_rules!)
+                None => {
+                    result.push('$');
+                    result.push_str(name);
+                }
+            },
+            Transcriber::Repeat(ref rep) => {
+                let mut names = Vec::new();
+                transcriber_vars(&rep.sub, &mut names);
+                let mut count = None;
+                for name in &names {
+                    if let Some(Binding::Repeated(seq)) = lookup(bindings, name, indices) {
+                        if count.is_some_and(|c| c != seq.len()) {
+                            return Err(format!(
+                                "meta-variable `{}` repeats {} times, but another one repeats {} times",
+                                name,
+                                seq.len(),
+                                count.unwrap()
+                            ));
+                        }
+                        count = Some(seq.len());
+                    }
+                }
+                let count = count.ok_or_else(|| {
+                    "attempted to repeat an expression containing no syntax variables matched as repeating at this depth".to_owned()
+                })?;
+                for i in 0..count {
+                    if i > 0 {
+                        if let Some(ref sep) = rep.separator {
+                            result.push_str(sep);
+                        }
+                    }
+                    indices.push(i);
+                    transcribe(&rep.sub, bindings, indices, result)?;
+                    indices.pop();
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Rule {
+    matcher: Vec<Matcher>,
+    transcriber: Vec<Transcriber>,
+    line: u32,
+    column: u32,
+}
+
+/// A `macro_rules!` definition
+#[derive(Debug)]
+pub struct MacroRules {
+    pub path: PathBuf, // The file in which the macro is defined
+    rules: Vec<Rule>,
+}
+
+/// The result of the expansion of a macro. `line` and `column` is the location of
+/// the transcriber in the file where the macro is defined.
+pub struct Expansion {
+    pub text: String,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl MacroRules {
+    /// Parse the body of a `macro_rules!` definition (the text within its delimiters).
+    /// Returns `None` if none of the rules invoke a macro for which `is_relevant` returns true.
+    pub fn parse(
+        body: Cursor,
+        path: PathBuf,
+        is_relevant: &dyn Fn(&str) -> bool,
+    ) -> Result<Option<MacroRules>, LineError> {
+        // Make the offsets of the tokens relative to the body
+        let body = Cursor { off: 0, ..body };
+        let tokens = tokenize(body)?;
+        if !invokes_macro(&tokens, is_relevant) {
+            return Ok(None);
+        }
+
+        let mut rules = Vec::new();
+        let mut i = 0;
+        while i < tokens.len() {
+            let invalid = |t: &Token| LineError(t.line, "invalid macro_rules! definition".into());
+            let (matcher, transcriber) = match (tokens.get(i), tokens.get(i + 3)) {
+                (
+                    Some(Token {
+                        kind: TokenKind::Group(_, ref matcher),
+                        ..
+                    }),
+                    Some(transcriber),
+                ) if tokens[i + 1].is_punct("=") && tokens[i + 2].is_punct(">") => {
+                    (matcher, transcriber)
+                }
+                _ => return Err(invalid(&tokens[i])),
+            };
+            let inner = match transcriber.kind {
+                TokenKind::Group(_, ref inner) => inner,
+                _ => return Err(invalid(transcriber)),
+            };
+            let mut pos = transcriber.start + 1;
+            let mut parsed = Vec::new();
+            parse_transcriber(
+                body.rest,
+                inner,
+                &mut pos,
+                transcriber.end() - 1,
+                &mut parsed,
+            )?;
+            rules.push(Rule {
+                matcher: parse_matcher(matcher)?,
+                transcriber: parsed,
+                line: transcriber.line,
+                column: transcriber.column + 1,
+            });
+            i += 4;
+            if tokens.get(i).is_some_and(|t| t.is_punct(";")) {
+                i += 1;
+            }
+        }
+        Ok(Some(MacroRules { path, rules }))
+    }
+
+    /// Expand the invocation whose arguments are `input` (the text within the delimiters)
+    pub fn expand(&self, input: Cursor) -> Result<Expansion, LineError> {
+        let tokens = tokenize(input)?;
+        for rule in &self.rules {
+            let bindings = match_seq(&input, &rule.matcher, &tokens, &mut |r| {
+                if r.is_empty() {
+                    Some(Bindings::new())
+                } else {
+                    None
+                }
+            });
+            if let Some(bindings) = bindings {
+                let mut text = String::new();
+                transcribe(&rule.transcriber, &bindings, &mut Vec::new(), &mut text)
+                    .map_err(|e| LineError(input.line, e))?;
+                return Ok(Expansion {
+                    text,
+                    line: rule.line,
+                    column: rule.column,
+                });
+            }
+        }
+        Err(LineError(
+            input.line,
+            "no rule of the macro matches this invocation".into(),
+        ))
+    }
+}
+
+#[cfg(test)]
+fn expand_for_test(definition: &str, invocation: &str) -> Result<String, LineError> {
+    let rules = MacroRules::parse(super::new_cursor(definition), PathBuf::new(), &|_| true)?
+        .expect("relevant macro");
+    rules.expand(super::new_cursor(invocation)).map(|e| e.text)
+}
+
+#[test]
+fn test_expand_macro_rules() {
+    let def = r#"
+        ($name:ident, $ty:ty, $cpp:tt) => {
+            fn $name() -> $ty { unsafe { cpp!([] -> $ty as $cpp {
+                #include <foo>
+                return 0; }) } }
+        };
+        ($($name:ident = $val:expr),* $(,)?) => { $(cpp!([] { $name($val); });)* }
+    "#;
+    assert_eq!(
+        expand_for_test(def, r#"foo, Option<u32>, "int""#).unwrap(),
+        r#"
+            fn foo() -> Option<u32> { unsafe { cpp!([] -> Option<u32> as "int" {
+                #include <foo>
+                return 0; }) } }
+        "#
+    );
+    assert_eq!(
+        expand_for_test(def, "a = 1 + 2, b = \"x\",").unwrap(),
+        " cpp!([] { a(1 + 2); });cpp!([] { b(\"x\"); }); "
+    );
+    assert!(expand_for_test(def, "a b c").is_err());
+    assert!(expand_for_test("($x) => { cpp!() }", "").is_err());
+}
+
+#[test]
+fn test_invokes_macro() {
+    let is_cpp = |name: &str| name == "cpp";
+    let tokens = tokenize(super::new_cursor("() => { foo!(); { cpp!{{ }} } }")).unwrap();
+    assert!(invokes_macro(&tokens, &is_cpp));
+    let tokens = tokenize(super::new_cursor("() => { cpp(); \"cpp!()\" }")).unwrap();
+    assert!(!invokes_macro(&tokens, &is_cpp));
+}
@@ -30,7 +30,7 @@ impl<'a> Cursor<'a> {
             line: self.line
                 + self.rest[..amt]
                     .char_indices()
-                    .filter(|&(_, ref x)| *x == '\n')
+                    .filter(|(_, x)| *x == '\n')
                     .map(|(i, _)| column_start = Some(i))
                     .count() as u32,
             column: match column_start {
@@ -129,7 +129,7 @@ pub fn whitespace(input: Cursor) -> PResult<()> {
     Ok((input.advance(input.len()), ()))
 }
 
-pub fn block_comment(input: Cursor) -> PResult<&str> {
+pub fn block_comment(input: Cursor<'_>) -> PResult<'_, &str> {
     if !input.starts_with("/*") {
         return Err(LexError { line: input.line });
     }
@@ -170,22 +170,22 @@ fn is_whitespace(ch: char) -> bool {
 
 #[inline]
 fn is_ident_start(c: char) -> bool {
-    ('a' <= c && c <= 'z')
-        || ('A' <= c && c <= 'Z')
+    c.is_ascii_lowercase()
+        || c.is_ascii_uppercase()
         || c == '_'
         || (c > '\x7f' && UnicodeXID::is_xid_start(c))
 }
 
 #[inline]
 fn is_ident_continue(c: char) -> bool {
-    ('a' <= c && c <= 'z')
-        || ('A' <= c && c <= 'Z')
+    c.is_ascii_lowercase()
+        || c.is_ascii_uppercase()
         || c == '_'
-        || ('0' <= c && c <= '9')
+        || c.is_ascii_digit()
         || (c > '\x7f' && UnicodeXID::is_xid_continue(c))
 }
 
-pub fn symbol(input: Cursor) -> PResult<&str> {
+pub fn symbol(input: Cursor<'_>) -> PResult<'_, &str> {
     let mut chars = input.char_indices();
 
     let raw = input.starts_with("r#");
@@ -305,7 +305,7 @@ pub fn cooked_byte_string(mut input: Cursor) -> PResult<()> {
 pub fn raw_string(input: Cursor) -> PResult<()> {
     let mut chars = input.char_indices();
     let mut n = 0;
-    while let Some((byte_offset, ch)) = chars.next() {
+    for (byte_offset, ch) in chars.by_ref() {
         match ch {
             '"' => {
                 n = byte_offset;
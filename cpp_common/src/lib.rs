@@ -11,19 +11,28 @@ extern crate proc_macro2;
 extern crate lazy_static;
 
 use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::path::PathBuf;
 
-use proc_macro2::{Span, TokenStream, TokenTree};
+use proc_macro2::{Delimiter, Span, TokenStream, TokenTree};
+use quote::ToTokens;
 use syn::ext::IdentExt;
 use syn::parse::{Parse, ParseStream, Result};
-use syn::{Attribute, Ident, Type};
+use syn::{Attribute, Expr, Ident, Type};
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 pub const LIB_NAME: &str = "librust_cpp_generated.a";
 pub const MSVC_LIB_NAME: &str = "rust_cpp_generated.lib";
+/// The file in which the build script writes the `cpp_type!` aliases of the crate, one
+/// `name=C++ type` per line, so that the macros can resolve them too
+pub const TYPE_ALIASES_NAME: &str = "rust_cpp_type_aliases.txt";
+/// The file in which the build script writes the macro_rules! invocations it cannot expand,
+/// one per line, so that the macros which are not found can name them
+pub const EXPANSION_ERRORS_NAME: &str = "rust_cpp_expansion_errors.txt";
 
 pub mod flags {
     pub const IS_COPY_CONSTRUCTIBLE: u32 = 0;
@@ -31,11 +40,24 @@ pub mod flags {
     pub const IS_TRIVIALLY_DESTRUCTIBLE: u32 = 2;
     pub const IS_TRIVIALLY_COPYABLE: u32 = 3;
     pub const IS_TRIVIALLY_DEFAULT_CONSTRUCTIBLE: u32 = 4;
+    pub const IS_INTEGRAL: u32 = 5;
+    pub const IS_SIGNED: u32 = 6;
+    pub const IS_FLOATING_POINT: u32 = 7;
+    pub const IS_POINTER: u32 = 8;
+    pub const IS_REFERENCE: u32 = 9;
+    pub const IS_POLYMORPHIC: u32 = 10;
+    /// Not a property of the type: set on the only record of a macro found in a file
+    /// excluded by the build configuration
+    pub const IS_EXCLUDED: u32 = 31;
+    /// Not a property of the type: set on the record of a `cpp_const!` whose value is
+    /// negative
+    pub const IS_NEGATIVE: u32 = 30;
 }
 
 pub mod kw {
     #![allow(non_camel_case_types)]
     custom_keyword!(rust);
+    custom_keyword!(auto);
 }
 
 /// This constant is expected to be a unique string within the compiled binary
@@ -71,31 +93,334 @@ NOTE: rustc must be run by Cargo."#
         OUT_DIR.hash(&mut hasher);
         hasher.finish()
     };
+    static ref RUST_CALLBACK_PREFIX: String = {
+        let name = env::var("CARGO_PKG_NAME").unwrap_or_default();
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        env::var("CARGO_PKG_VERSION")
+            .unwrap_or_default()
+            .hash(&mut hasher);
+        let name: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        format!("rust_cpp_{}_{:x}_", name, hasher.finish())
+    };
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+/// The name of the symbol of a `rust!` callback within a `cpp!{{ }}` snippet.
+/// It is prefixed with the package name and version, so that several crates can use
+/// the same callback names in one binary.
+pub fn rust_callback_symbol(id: &Ident) -> String {
+    format!("{}{}", *RUST_CALLBACK_PREFIX, id)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Capture {
+    /// The rust expression bound to the parameter, if any (`self.width => w as "int"`).
+    /// Otherwise the captured variable has the same name as the parameter.
+    pub expr: Option<Expr>,
+    /// The value is moved into the C++ code (`move foo as "T"`), which receives a `T&&`
+    pub moved: bool,
     pub mutable: bool,
     pub name: Ident,
-    pub cpp: String,
+    /// The rust type, if annotated (`foo: i32`)
+    pub ty: Option<Type>,
+    /// The C++ type, or `None` if it is to be inferred from the rust type by the build
+    /// script (`foo: i32 as auto`, or just `foo: i32`)
+    pub cpp: Option<CppType>,
+}
+
+/// The C++ type of a capture, as written in the `cpp!` macro
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum CppType {
+    /// Example: `foo as "const ns::Widget*"`
+    Literal(String),
+    /// The name of a `cpp_type!` alias. Example: `foo as WidgetPtr`
+    Alias(Ident),
+}
+
+/// Hash the tokens of an expression or a type, without their invisible groups. The fragments
+/// of a macro_rules! (`$e:expr`, `$t:ty`) are in such groups, at any depth, for the macros,
+/// but not in the textual expansion of the build script, so the hashes must ignore them. The
+/// groups are kept in the syntax tree, since they give the precedence of the fragments.
+fn hash_tokens<H: Hasher>(tokens: TokenStream, state: &mut H) {
+    for token in tokens {
+        match token {
+            TokenTree::Group(g) if g.delimiter() == Delimiter::None => {
+                hash_tokens(g.stream(), state)
+            }
+            TokenTree::Group(g) => {
+                let delimiter = match g.delimiter() {
+                    Delimiter::Parenthesis => '(',
+                    Delimiter::Brace => '{',
+                    Delimiter::Bracket => '[',
+                    Delimiter::None => unreachable!(),
+                };
+                delimiter.hash(state);
+                hash_tokens(g.stream(), state);
+                ')'.hash(state);
+            }
+            TokenTree::Ident(i) => {
+                0u8.hash(state);
+                i.to_string().hash(state);
+            }
+            TokenTree::Punct(p) => p.as_char().hash(state),
+            TokenTree::Literal(l) => {
+                1u8.hash(state);
+                l.to_string().hash(state);
+            }
+        }
+    }
+}
+
+fn hash_optional_tokens<T: ToTokens, H: Hasher>(tokens: &Option<T>, state: &mut H) {
+    tokens.is_some().hash(state);
+    if let Some(tokens) = tokens {
+        hash_tokens(tokens.to_token_stream(), state);
+        // The end of the tokens, so that consecutive token streams are distinguished
+        2u8.hash(state);
+    }
+}
+
+impl Hash for Capture {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        hash_optional_tokens(&self.expr, state);
+        self.moved.hash(state);
+        self.mutable.hash(state);
+        self.name.hash(state);
+        hash_optional_tokens(&self.ty, state);
+        self.cpp.hash(state);
+    }
+}
+
+impl fmt::Display for CppType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CppType::Literal(s) => f.write_str(s),
+            CppType::Alias(i) => write!(f, "{}", i),
+        }
+    }
 }
 
 impl Parse for Capture {
     /// Parse a single captured variable inside within a `cpp!` macro.
-    /// Example: `mut foo as "int"`
+    /// Example: `mut foo as "int"`, `foo as WidgetPtr`, `foo: i32 as auto`, `foo: i32`,
+    /// `self.foo => foo as "int"`, or `move foo as "T"`
     fn parse(input: ParseStream) -> Result<Self> {
-        Ok(Capture {
+        let capture = Capture {
+            expr: {
+                let fork = input.fork();
+                if fork.parse::<Expr>().is_ok() && fork.peek(Token![=>]) {
+                    let expr: Expr = input.parse()?;
+                    input.parse::<Token![=>]>()?;
+                    Some(expr)
+                } else {
+                    None
+                }
+            },
+            moved: input.parse::<Option<Token![move]>>()?.is_some(),
             mutable: input.parse::<Option<Token![mut]>>()?.is_some(),
             name: input.call(Ident::parse_any)?,
-            cpp: {
-                input.parse::<Token![as]>()?;
-                input.parse::<syn::LitStr>()?.value()
+            ty: if input.parse::<Option<Token![:]>>()?.is_some() {
+                let mut t: Type = input.parse()?;
+                while let Type::Group(g) = t {
+                    t = *g.elem;
+                }
+                Some(t)
+            } else {
+                None
             },
-        })
+            cpp: parse_cpp_type(input)?,
+        };
+        if capture.moved && capture.mutable {
+            return Err(syn::Error::new(
+                capture.name.span(),
+                "a `move` capture is already mutable",
+            ));
+        }
+        Ok(capture)
     }
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+/// Parse the optional C++ type following a rust type: `as "T"`, `as Alias` or `as auto`
+fn parse_cpp_type(input: ParseStream) -> Result<Option<CppType>> {
+    if input.parse::<Option<Token![as]>>()?.is_none()
+        || input.parse::<Option<kw::auto>>()?.is_some()
+    {
+        Ok(None)
+    } else if input.peek(Ident) {
+        Ok(Some(CppType::Alias(input.parse()?)))
+    } else {
+        Ok(Some(CppType::Literal(
+            input.parse::<syn::LitStr>()?.value(),
+        )))
+    }
+}
+
+/// The C++ types of the rust primitive types and of the `std::os::raw` types, used to infer
+/// the C++ type of a capture from its rust type.
+const PRIMITIVE_CPP_TYPES: &[(&str, &str)] = &[
+    ("i8", "int8_t"),
+    ("i16", "int16_t"),
+    ("i32", "int32_t"),
+    ("i64", "int64_t"),
+    ("isize", "intptr_t"),
+    ("u8", "uint8_t"),
+    ("u16", "uint16_t"),
+    ("u32", "uint32_t"),
+    ("u64", "uint64_t"),
+    ("usize", "uintptr_t"),
+    ("f32", "float"),
+    ("f64", "double"),
+    ("bool", "bool"),
+    ("char", "char32_t"),
+    ("c_char", "char"),
+    ("c_schar", "signed char"),
+    ("c_uchar", "unsigned char"),
+    ("c_short", "short"),
+    ("c_ushort", "unsigned short"),
+    ("c_int", "int"),
+    ("c_uint", "unsigned int"),
+    ("c_long", "long"),
+    ("c_ulong", "unsigned long"),
+    ("c_longlong", "long long"),
+    ("c_ulonglong", "unsigned long long"),
+    ("c_float", "float"),
+    ("c_double", "double"),
+    ("c_void", "void"),
+    ("StrView", "rustcpp::str_view"),
+    ("CppString", "rustcpp::string"),
+];
+
+/// The generic types of the `cpp` crate whose C++ type is inferred from their parameters
+const GENERIC_CPP_TYPES: &[&str] = &["DynRef", "CppFunction", "Slice", "SliceMut"];
+
+/// Whether `infer_cpp_type` knows the C++ type of the rust types of this name by itself.
+/// A type declared with such a name makes the inference ambiguous.
+pub fn is_builtin_type_name(name: &str) -> bool {
+    GENERIC_CPP_TYPES.contains(&name) || PRIMITIVE_CPP_TYPES.iter().any(|(rust, _)| *rust == name)
+}
+
+/// The C++ type of a type declared in the crate (e.g. with `cpp_class!`). The declared
+/// types are looked up by the last segment of their rust path, which may be ambiguous.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeclaredType {
+    Unknown,
+    Cpp(String),
+    /// Several types of this name are declared, so the C++ type must be explicit
+    Ambiguous,
+}
+
+/// Infer the C++ type corresponding to a rust type: either a primitive type, a pointer to
+/// such a type, one of the types of the `cpp` crate, or one of the types in `classes` (a
+/// function which returns the C++ type of a `cpp_class!` given its name).
+/// Returns `None` if the type is not known, or if its name is ambiguous.
+pub fn infer_cpp_type(ty: &Type, classes: &dyn Fn(&Ident) -> DeclaredType) -> Option<String> {
+    match ty {
+        Type::Path(p) if p.qself.is_none() => {
+            let last = p.path.segments.last()?;
+            let declared = match classes(&last.ident) {
+                DeclaredType::Ambiguous => return None,
+                DeclaredType::Cpp(cpp) => Some(cpp),
+                DeclaredType::Unknown => None,
+            };
+            if last.ident == "DynRef" {
+                return Some("rustcpp::dyn_ref".to_owned());
+            }
+            let mut lifetimes_only = true;
+            if let syn::PathArguments::AngleBracketed(args) = &last.arguments {
+                for arg in &args.args {
+                    match arg {
+                        syn::GenericArgument::Lifetime(_) => {}
+                        syn::GenericArgument::Type(Type::TraitObject(t))
+                            if last.ident == "CppFunction" && args.args.len() == 1 =>
+                        {
+                            return infer_function_type(t, classes);
+                        }
+                        // The slices of the `cpp` crate
+                        syn::GenericArgument::Type(t) if args.args.len() <= 2 => {
+                            let elem = infer_cpp_type(t, classes)?;
+                            return match last.ident.to_string().as_str() {
+                                "Slice" => Some(format!("rustcpp::slice<{} const>", elem)),
+                                "SliceMut" => Some(format!("rustcpp::slice<{}>", elem)),
+                                _ => None,
+                            };
+                        }
+                        _ => lifetimes_only = false,
+                    }
+                }
+            } else if !last.arguments.is_empty() {
+                lifetimes_only = false;
+            }
+            if !lifetimes_only {
+                return None;
+            }
+            PRIMITIVE_CPP_TYPES
+                .iter()
+                .find(|(rust, _)| last.ident == rust)
+                .map(|(_, cpp)| (*cpp).to_owned())
+                .or(declared)
+        }
+        Type::Ptr(p) => {
+            let pointee = infer_cpp_type(&p.elem, classes)?;
+            if p.mutability.is_some() {
+                Some(format!("{} *", pointee))
+            } else {
+                Some(format!("{} const*", pointee))
+            }
+        }
+        Type::Paren(p) => infer_cpp_type(&p.elem, classes),
+        Type::Group(g) => infer_cpp_type(&g.elem, classes),
+        _ => None,
+    }
+}
+
+/// The `rustcpp::function` for the `dyn FnMut(A...) -> R` of a `CppFunction`
+fn infer_function_type(
+    t: &syn::TypeTraitObject,
+    classes: &dyn Fn(&Ident) -> DeclaredType,
+) -> Option<String> {
+    let mut bounds = t.bounds.iter().filter_map(|b| match b {
+        syn::TypeParamBound::Trait(b) => Some(b),
+        _ => None,
+    });
+    let bound = bounds.next()?;
+    let last = bound.path.segments.last()?;
+    let sig = match &last.arguments {
+        syn::PathArguments::Parenthesized(sig) if last.ident == "FnMut" => sig,
+        _ => return None,
+    };
+    if bounds.next().is_some() {
+        return None;
+    }
+    let ret = match &sig.output {
+        syn::ReturnType::Default => "void".to_owned(),
+        syn::ReturnType::Type(_, ty) => infer_cpp_type(ty, classes)?,
+    };
+    let args = sig
+        .inputs
+        .iter()
+        .map(|ty| infer_cpp_type(ty, classes))
+        .collect::<Option<Vec<_>>>()?;
+    Some(format!("rustcpp::function<{}({})>", ret, args.join(", ")))
+}
+
+/// The number of records of the signature of a `rustcpp::function` capture: the return type
+/// and at most 6 arguments
+pub const FUNCTION_SIGNATURE_RECORDS: usize = 7;
+
+/// Whether the explicit C++ type of a capture is a `rustcpp::function`. The metadata of the
+/// closure then has the `FUNCTION_SIGNATURE_RECORDS` records of its signature, after the
+/// records of the captures.
+pub fn is_function_type(cpp: &str) -> bool {
+    let cpp = cpp.trim_start();
+    cpp.strip_prefix("::")
+        .unwrap_or(cpp)
+        .starts_with("rustcpp::function<")
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ClosureSig {
     pub captures: Vec<Capture>,
     pub ret: Option<Type>,
@@ -103,7 +428,28 @@ pub struct ClosureSig {
     pub std_body: String,
 }
 
+impl Hash for ClosureSig {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.captures.hash(state);
+        hash_optional_tokens(&self.ret, state);
+        self.cpp.hash(state);
+        self.std_body.hash(state);
+    }
+}
+
 impl ClosureSig {
+    /// Replace the `cpp_type!` aliases of the captures by their C++ types, so that the hash
+    /// depends on the C++ types. The unknown aliases are kept.
+    pub fn resolve_type_aliases(&mut self, aliases: &HashMap<String, String>) {
+        for capture in &mut self.captures {
+            if let Some(CppType::Alias(alias)) = &capture.cpp {
+                if let Some(cpp) = aliases.get(&alias.to_string()) {
+                    capture.cpp = Some(CppType::Literal(cpp.clone()));
+                }
+            }
+        }
+    }
+
     pub fn name_hash(&self) -> u64 {
         // XXX: Use a better hasher than the default?
         let mut hasher = DefaultHasher::new();
@@ -175,12 +521,93 @@ impl Parse for Closure {
     }
 }
 
+/// The standard C++ types for which `cpp_class!` can generate an API, given the rust
+/// type of their elements
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClassWrapper {
+    /// `vector_of T`: a `std::vector<T>`
+    Vector,
+    /// `unique_ptr_of T`: a `std::unique_ptr<T>`
+    UniquePtr,
+    /// `shared_ptr_of T`: a `std::shared_ptr<T>`
+    SharedPtr,
+}
+
+/// The kind of an operator which can be derived on a `cpp_class!`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperatorKind {
+    /// `-a`, which returns a new object
+    Unary,
+    /// `a + b`, which returns a new object
+    Binary,
+    /// `a += b`
+    Assign,
+}
+
+/// The operators which can be derived on a `cpp_class!`: the name of the rust trait, of its
+/// method, and the C++ operator.
+pub const CLASS_OPERATORS: &[(&str, &str, &str, OperatorKind)] = &[
+    ("Add", "add", "+", OperatorKind::Binary),
+    ("Sub", "sub", "-", OperatorKind::Binary),
+    ("Mul", "mul", "*", OperatorKind::Binary),
+    ("Div", "div", "/", OperatorKind::Binary),
+    ("Rem", "rem", "%", OperatorKind::Binary),
+    ("BitAnd", "bitand", "&", OperatorKind::Binary),
+    ("BitOr", "bitor", "|", OperatorKind::Binary),
+    ("BitXor", "bitxor", "^", OperatorKind::Binary),
+    ("Shl", "shl", "<<", OperatorKind::Binary),
+    ("Shr", "shr", ">>", OperatorKind::Binary),
+    ("AddAssign", "add_assign", "+=", OperatorKind::Assign),
+    ("SubAssign", "sub_assign", "-=", OperatorKind::Assign),
+    ("MulAssign", "mul_assign", "*=", OperatorKind::Assign),
+    ("DivAssign", "div_assign", "/=", OperatorKind::Assign),
+    ("RemAssign", "rem_assign", "%=", OperatorKind::Assign),
+    ("BitAndAssign", "bitand_assign", "&=", OperatorKind::Assign),
+    ("BitOrAssign", "bitor_assign", "|=", OperatorKind::Assign),
+    ("BitXorAssign", "bitxor_assign", "^=", OperatorKind::Assign),
+    ("ShlAssign", "shl_assign", "<<=", OperatorKind::Assign),
+    ("ShrAssign", "shr_assign", ">>=", OperatorKind::Assign),
+    ("Neg", "neg", "-", OperatorKind::Unary),
+];
+
+/// A field of a `cpp_class!`, declared with the `#[cpp_fields(...)]` attribute
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct ClassField {
+    /// The visibility of the generated accessors. They are not generated for private fields.
+    pub vis: syn::Visibility,
+    pub name: Ident,
+    pub ty: Type,
+}
+
+impl Parse for ClassField {
+    /// Example: `pub x: i32`
+    fn parse(input: ParseStream) -> Result<Self> {
+        let vis = input.parse()?;
+        let name = input.parse()?;
+        input.parse::<Token![:]>()?;
+        let mut ty: Type = input.parse()?;
+        while let Type::Group(g) = ty {
+            ty = *g.elem;
+        }
+        Ok(ClassField { vis, name, ty })
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Class {
     pub name: Ident,
     pub cpp: String,
     pub attrs: Vec<Attribute>,
     pub line: String, // the #line directive
+    /// The kind of standard type, and the rust type of the elements
+    pub element: Option<(ClassWrapper, Type)>,
+    /// The leading fields of the C++ class, in order
+    pub fields: Vec<ClassField>,
+    /// The rust types of the index and of the elements of `operator[]`, declared with
+    /// `#[cpp_index(Idx => Output)]`
+    pub index: Option<(Type, Type)>,
+    /// The `cpp_class!` of the base class (`as "Derived" : Base`)
+    pub base: Option<Type>,
 }
 
 impl Class {
@@ -188,13 +615,16 @@ impl Class {
         let mut hasher = DefaultHasher::new();
         self.name.hash(&mut hasher);
         self.cpp.hash(&mut hasher);
+        self.fields.hash(&mut hasher);
+        self.index.hash(&mut hasher);
+        self.base.hash(&mut hasher);
         hasher.finish()
     }
 
     pub fn derives(&self, i: &str) -> bool {
         self.attrs.iter().any(|x| {
             use syn::{Meta, NestedMeta};
-            x.parse_meta().ok().map_or(false, |m| {
+            x.parse_meta().ok().is_some_and(|m| {
                 if let Meta::List(ref list) = m {
                     list.path.is_ident("derive")
                         && list.nested.iter().any(|y| {
@@ -214,10 +644,30 @@ impl Class {
 
 impl Parse for Class {
     /// Parse the inside of a `cpp_class!` macro.
-    /// Example: `#[derive(Default)] pub unsafe struct Foobar as "FooBar"`
+    /// Example: `#[derive(Default)] pub unsafe struct Foobar as "FooBar"`,
+    /// `pub unsafe struct Derived as "Derived" : Base`,
+    /// or `pub unsafe struct VecInt as "std::vector<int>" vector_of i32`
     fn parse(input: ParseStream) -> Result<Self> {
+        let attrs = input.call(Attribute::parse_outer)?;
+        let mut fields = vec![];
+        let mut index = None;
+        for attr in &attrs {
+            if attr.path.is_ident("cpp_fields") {
+                fields.extend(attr.parse_args_with(
+                    syn::punctuated::Punctuated::<ClassField, Token![,]>::parse_terminated,
+                )?);
+            } else if attr.path.is_ident("cpp_index") {
+                index = Some(attr.parse_args_with(|input: ParseStream| {
+                    let idx = input.parse()?;
+                    input.parse::<Token![=>]>()?;
+                    Ok((idx, input.parse()?))
+                })?);
+            }
+        }
         Ok(Class {
-            attrs: input.call(Attribute::parse_outer)?,
+            attrs,
+            fields,
+            index,
             name: {
                 input.parse::<syn::Visibility>()?;
                 input.parse::<Token![unsafe]>()?;
@@ -229,6 +679,404 @@ impl Parse for Class {
                 input.parse::<syn::LitStr>()?.value()
             },
             line: String::new(),
+            base: if input.parse::<Option<Token![:]>>()?.is_some() {
+                let mut t: Type = input.parse()?;
+                while let Type::Group(g) = t {
+                    t = *g.elem;
+                }
+                Some(t)
+            } else {
+                None
+            },
+            element: if input.is_empty() {
+                None
+            } else {
+                let kind = input.parse::<Ident>()?;
+                let wrapper = if kind == "vector_of" {
+                    ClassWrapper::Vector
+                } else if kind == "unique_ptr_of" {
+                    ClassWrapper::UniquePtr
+                } else if kind == "shared_ptr_of" {
+                    ClassWrapper::SharedPtr
+                } else {
+                    return Err(syn::Error::new(
+                        kind.span(),
+                        "expected `vector_of`, `unique_ptr_of` or `shared_ptr_of`",
+                    ));
+                };
+                let mut t: Type = input.parse()?;
+                while let Type::Group(g) = t {
+                    t = *g.elem;
+                }
+                Some((wrapper, t))
+            },
+        })
+    }
+}
+
+/// A named C++ type, which captures can refer to instead of repeating the C++ type
+#[derive(Clone, Debug)]
+pub struct TypeAlias {
+    pub name: Ident,
+    pub cpp: String,
+}
+
+impl Parse for TypeAlias {
+    /// Parse the inside of a `cpp_type!` macro.
+    /// Example: `WidgetPtr = "const ns::Widget*"`
+    fn parse(input: ParseStream) -> Result<Self> {
+        let name = input.parse()?;
+        input.parse::<Token![=]>()?;
+        let cpp = input.parse::<syn::LitStr>()?.value();
+        Ok(TypeAlias { name, cpp })
+    }
+}
+
+/// A virtual function of the C++ base class of a `cpp_impl!`, implemented by a method
+/// of the rust trait
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct ImplMethod {
+    /// The name of the C++ virtual function
+    pub cpp_name: Ident,
+    pub name: Ident,
+    /// The method takes `&mut self`, so the C++ function is not `const`
+    pub mutable: bool,
+    /// The name, rust type and C++ type of the arguments
+    pub args: Vec<(Ident, Type, Option<CppType>)>,
+    /// The rust and C++ return types
+    pub ret: Option<(Type, Option<CppType>)>,
+}
+
+impl Parse for ImplMethod {
+    /// Example: `computeValue => fn compute_value(&self, x: i32 as "int") -> i32 as "int"`
+    fn parse(input: ParseStream) -> Result<Self> {
+        let cpp_name = input.call(Ident::parse_any)?;
+        input.parse::<Token![=>]>()?;
+        input.parse::<Token![fn]>()?;
+        let name = input.parse()?;
+        let content;
+        parenthesized!(content in input);
+        content.parse::<Token![&]>()?;
+        let mutable = content.parse::<Option<Token![mut]>>()?.is_some();
+        content.parse::<Token![self]>()?;
+        let mut args = vec![];
+        while content.parse::<Option<Token![,]>>()?.is_some() && !content.is_empty() {
+            let arg = content.call(Ident::parse_any)?;
+            content.parse::<Token![:]>()?;
+            let ty = content.parse()?;
+            args.push((arg, ty, parse_cpp_type(&content)?));
+        }
+        if !content.is_empty() {
+            return Err(content.error("expected `,`"));
+        }
+        let ret = if input.parse::<Option<Token![->]>>()?.is_some() {
+            let ty = input.parse()?;
+            Some((ty, parse_cpp_type(input)?))
+        } else {
+            None
+        };
+        Ok(ImplMethod {
+            cpp_name,
+            name,
+            mutable,
+            args,
+            ret,
+        })
+    }
+}
+
+/// A C++ subclass implementing virtual functions with the methods of a rust trait
+#[derive(Clone, Debug)]
+pub struct Impl {
+    pub name: Ident,
+    /// The C++ base class
+    pub cpp: String,
+    pub attrs: Vec<Attribute>,
+    pub line: String, // the #line directive
+    /// The path of the rust trait
+    pub trait_path: syn::Path,
+    pub methods: Vec<ImplMethod>,
+}
+
+impl Impl {
+    pub fn name_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.name.hash(&mut hasher);
+        self.cpp.hash(&mut hasher);
+        self.trait_path.hash(&mut hasher);
+        self.methods.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl Parse for Impl {
+    /// Parse the inside of a `cpp_impl!` macro.
+    /// Example: `pub unsafe struct Foo as "Base" for dyn Trait { virtualFn => fn method(&self); }`
+    fn parse(input: ParseStream) -> Result<Self> {
+        let attrs = input.call(Attribute::parse_outer)?;
+        input.parse::<syn::Visibility>()?;
+        input.parse::<Token![unsafe]>()?;
+        input.parse::<Token![struct]>()?;
+        let name = input.parse()?;
+        input.parse::<Token![as]>()?;
+        let cpp = input.parse::<syn::LitStr>()?.value();
+        input.parse::<Token![for]>()?;
+        input.parse::<Token![dyn]>()?;
+        let trait_path = input.parse()?;
+        let content;
+        braced!(content in input);
+        let methods =
+            syn::punctuated::Punctuated::<ImplMethod, Token![;]>::parse_terminated(&content)?
+                .into_iter()
+                .collect();
+        Ok(Impl {
+            name,
+            cpp,
+            attrs,
+            line: String::new(),
+            trait_path,
+            methods,
+        })
+    }
+}
+
+/// A rust constant with the value of a C++ constant expression
+#[derive(Clone, Debug)]
+pub struct Const {
+    pub name: Ident,
+    pub ty: Type,
+    /// The C++ expression
+    pub cpp: String,
+    pub line: String, // the #line directive
+}
+
+impl Const {
+    pub fn name_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.name.hash(&mut hasher);
+        self.ty.hash(&mut hasher);
+        self.cpp.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl Parse for Const {
+    /// Parse the inside of a `cpp_const!` macro.
+    /// Example: `pub const FOO_MAX: u32 = "FOO_MAX"`
+    fn parse(input: ParseStream) -> Result<Self> {
+        input.call(Attribute::parse_outer)?;
+        input.parse::<syn::Visibility>()?;
+        input.parse::<Token![const]>()?;
+        let name = input.parse()?;
+        input.parse::<Token![:]>()?;
+        let mut ty: Type = input.parse()?;
+        while let Type::Group(g) = ty {
+            ty = *g.elem;
+        }
+        input.parse::<Token![=]>()?;
+        let cpp = input.parse::<syn::LitStr>()?.value();
+        input.parse::<Option<Token![;]>>()?;
+        Ok(Const {
+            name,
+            ty,
+            cpp,
+            line: String::new(),
+        })
+    }
+}
+
+/// A rust enum mirroring a C++ enum
+#[derive(Clone, Debug)]
+pub struct Enum {
+    pub name: Ident,
+    /// The rust integer type of the `#[repr]`, if specified (`enum Color: u8 as "Color"`)
+    pub ty: Option<Ident>,
+    pub cpp: String,
+    pub variants: Vec<Ident>,
+    pub line: String, // the #line directive
+}
+
+impl Enum {
+    pub fn name_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.name.hash(&mut hasher);
+        self.ty.hash(&mut hasher);
+        self.cpp.hash(&mut hasher);
+        self.variants.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl Parse for Enum {
+    /// Parse the inside of a `cpp_enum!` macro.
+    /// Example: `pub enum Color as "ns::Color" { Red, Green, Blue }`
+    fn parse(input: ParseStream) -> Result<Self> {
+        input.call(Attribute::parse_outer)?;
+        input.parse::<syn::Visibility>()?;
+        input.parse::<Token![enum]>()?;
+        let name = input.parse()?;
+        let ty = if input.parse::<Option<Token![:]>>()?.is_some() {
+            Some(input.parse()?)
+        } else {
+            None
+        };
+        input.parse::<Token![as]>()?;
+        let cpp = input.parse::<syn::LitStr>()?.value();
+        let content;
+        braced!(content in input);
+        let variants = syn::punctuated::Punctuated::<Ident, Token![,]>::parse_terminated(&content)?
+            .into_iter()
+            .collect();
+        Ok(Enum {
+            name,
+            ty,
+            cpp,
+            variants,
+            line: String::new(),
+        })
+    }
+}
+
+/// A field of a `cpp_export_struct!`
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct StructField {
+    pub name: Ident,
+    pub ty: Type,
+    /// The C++ type, or `None` if it is to be inferred from the rust type
+    pub cpp: Option<CppType>,
+}
+
+impl Parse for StructField {
+    /// Example: `pub x: i32`, or `name: *const c_char as "const char*"`
+    fn parse(input: ParseStream) -> Result<Self> {
+        input.call(Attribute::parse_outer)?;
+        input.parse::<syn::Visibility>()?;
+        let name = input.parse()?;
+        input.parse::<Token![:]>()?;
+        let mut ty: Type = input.parse()?;
+        while let Type::Group(g) = ty {
+            ty = *g.elem;
+        }
+        Ok(StructField {
+            name,
+            ty,
+            cpp: parse_cpp_type(input)?,
+        })
+    }
+}
+
+/// A `#[repr(C)]` rust struct for which the build script generates the C++ definition
+#[derive(Clone, Debug)]
+pub struct ExportedStruct {
+    pub name: Ident,
+    /// The name of the C++ struct, which may be qualified by namespaces
+    pub cpp: String,
+    pub fields: Vec<StructField>,
+    pub line: String, // the #line directive
+}
+
+impl ExportedStruct {
+    pub fn name_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.name.hash(&mut hasher);
+        self.cpp.hash(&mut hasher);
+        self.fields.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl Parse for ExportedStruct {
+    /// Parse the inside of a `cpp_export_struct!` macro.
+    /// Example: `pub struct Point as "geo::Point" { pub x: i32, pub y: i32 }`
+    fn parse(input: ParseStream) -> Result<Self> {
+        input.call(Attribute::parse_outer)?;
+        input.parse::<syn::Visibility>()?;
+        input.parse::<Token![struct]>()?;
+        let name = input.parse()?;
+        input.parse::<Token![as]>()?;
+        let cpp = input.parse::<syn::LitStr>()?.value();
+        let content;
+        braced!(content in input);
+        let fields =
+            syn::punctuated::Punctuated::<StructField, Token![,]>::parse_terminated(&content)?
+                .into_iter()
+                .collect();
+        Ok(ExportedStruct {
+            name,
+            cpp,
+            fields,
+            line: String::new(),
+        })
+    }
+}
+
+/// An iteration over a C++ range, with `cpp_iter!`
+#[derive(Clone, Debug)]
+pub struct Iter {
+    /// The C++ type of the container
+    pub cpp: String,
+    /// The rust type of the items
+    pub item: Type,
+    pub line: String, // the #line directive
+}
+
+impl Iter {
+    /// The hash does not depend on the rust expression of the container, so the same
+    /// iteration in several places uses the same C++ functions
+    pub fn name_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.cpp.hash(&mut hasher);
+        self.item.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// The items are tuples, whose elements are converted separately
+    pub fn is_tuple(&self) -> bool {
+        matches!(iter_element(&self.item), (Type::Tuple(_), false))
+    }
+
+    /// The elements of the items: the types of a tuple, or the type of the items. The
+    /// elements which are references are borrowed from the container rather than copied,
+    /// and the referenced type is returned with `true`.
+    pub fn elements(&self) -> Vec<(&Type, bool)> {
+        match iter_element(&self.item) {
+            (Type::Tuple(t), false) => t.elems.iter().map(iter_element).collect(),
+            x => vec![x],
+        }
+    }
+}
+
+/// The type of an element of the items of a `cpp_iter!`, and whether it is a reference
+fn iter_element(mut ty: &Type) -> (&Type, bool) {
+    loop {
+        match ty {
+            Type::Group(g) => ty = &g.elem,
+            Type::Paren(p) => ty = &p.elem,
+            Type::Reference(r) => return (&r.elem, true),
+            _ => return (ty, false),
+        }
+    }
+}
+
+impl Parse for Iter {
+    /// Parse the inside of a `cpp_iter!` macro.
+    /// Example: `unsafe container as "const std::map<int, A>&" => (i32, &A)`
+    fn parse(input: ParseStream) -> Result<Self> {
+        input.parse::<Option<Token![unsafe]>>()?;
+        // The container is a rust identifier or expression in parentheses, which only
+        // matters to the rust side
+        input.parse::<TokenTree>()?;
+        input.parse::<Token![as]>()?;
+        let cpp = input.parse::<syn::LitStr>()?.value();
+        input.parse::<Token![=>]>()?;
+        let mut item: Type = input.parse()?;
+        while let Type::Group(g) = item {
+            item = *g.elem;
+        }
+        Ok(Iter {
+            cpp,
+            item,
+            line: String::new(),
         })
     }
 }
@@ -241,7 +1089,7 @@ pub enum Macro {
 }
 
 impl Parse for Macro {
-    ///! Parse the inside of a `cpp!` macro (a literal or a closure)
+    /// Parse the inside of a `cpp!` macro (a literal or a closure)
     fn parse(input: ParseStream) -> Result<Self> {
         if input.peek(syn::token::Brace) {
             let content;
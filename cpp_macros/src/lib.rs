@@ -10,7 +10,11 @@ extern crate syn;
 extern crate proc_macro;
 use proc_macro2::Span;
 
-use cpp_common::{flags, kw, RustInvocation, FILE_HASH, LIB_NAME, MSVC_LIB_NAME, OUT_DIR, VERSION};
+use cpp_common::{
+    flags, is_function_type, kw, rust_callback_symbol, ClassWrapper, CppType, OperatorKind,
+    RustInvocation, FILE_HASH, FUNCTION_SIGNATURE_RECORDS, LIB_NAME, MSVC_LIB_NAME, OUT_DIR,
+    TYPE_ALIASES_NAME, VERSION,
+};
 use std::collections::HashMap;
 use std::iter::FromIterator;
 use syn::parse::Parser;
@@ -23,6 +27,7 @@ use quote::{quote, quote_spanned};
 use std::fs::File;
 use std::io::{self, BufReader, Read, Seek, SeekFrom};
 
+#[derive(Clone, Copy)]
 struct MetaData {
     size: usize,
     align: usize,
@@ -64,6 +69,48 @@ NOTE: Did you make sure to add the rust-cpp build script?
 I/O error while reading metadata from target library file."#,
         )
     };
+
+    /// The `cpp_type!` aliases of the crate, as written by the build script
+    static ref TYPE_ALIASES: HashMap<String, String> =
+        std::fs::read_to_string(OUT_DIR.join(TYPE_ALIASES_NAME))
+            .map(|aliases| {
+                aliases
+                    .lines()
+                    .filter_map(|line| line.split_once('='))
+                    .map(|(name, cpp)| (name.to_owned(), cpp.to_owned()))
+                    .collect()
+            })
+            .unwrap_or_default();
+}
+
+#[cfg(not(feature = "docs-only"))]
+lazy_static! {
+    /// The macro_rules! invocations which the build script could not expand
+    static ref EXPANSION_ERRORS: Vec<String> =
+        std::fs::read_to_string(OUT_DIR.join(cpp_common::EXPANSION_ERRORS_NAME))
+            .map(|errors| errors.lines().map(str::to_owned).collect())
+            .unwrap_or_default();
+}
+
+/// The error of a rust-cpp macro which is not found in the metadata. It names the macro_rules!
+/// invocations which the build script could not expand, as the macro may come from one of them.
+#[cfg(not(feature = "docs-only"))]
+fn not_found_error(name: &str) -> proc_macro2::TokenStream {
+    let mut msg = format!(
+        "This {name}! macro is not found in the library's rust-cpp metadata.
+NOTE: Only {name}! macros found directly in the program source, or generated by a
+NOTE: macro_rules! macro of the same crate, will be parsed - They cannot be generated by
+NOTE: other kinds of macro expansion.",
+        name = name
+    );
+    if !EXPANSION_ERRORS.is_empty() {
+        msg.push_str("\nNOTE: The build script could not expand these macro_rules! invocations:");
+        for error in EXPANSION_ERRORS.iter() {
+            msg.push_str("\nNOTE: ");
+            msg.push_str(error);
+        }
+    }
+    quote!(compile_error! { #msg })
 }
 
 /// NOTE: This panics when it can produce a better error message
@@ -107,7 +154,7 @@ Version mismatch between cpp_macros and cpp_build for same crate."#
         let hash = file.read_u64::<LittleEndian>()?;
         let size = file.read_u64::<LittleEndian>()? as usize;
         let align = file.read_u64::<LittleEndian>()? as usize;
-        let flags = file.read_u64::<LittleEndian>()? as u64;
+        let flags = file.read_u64::<LittleEndian>()?;
 
         metadata
             .entry(hash)
@@ -128,6 +175,108 @@ fn open_lib_file() -> io::Result<File> {
     }
 }
 
+/// The kinds of the rust types compatible with a C++ type, as a pattern matching
+/// `cpp::TypeKind`. Returns None if the C++ type is not a primitive type or a pointer.
+/// (References are not checked, as a capture may be bound to a C++ reference to its type.)
+fn compatible_kinds(data: &MetaData) -> Option<proc_macro2::TokenStream> {
+    if data.has_flag(flags::IS_REFERENCE) {
+        None
+    } else if data.has_flag(flags::IS_POINTER) {
+        Some(quote!(::cpp::TypeKind::Pointer))
+    } else if data.has_flag(flags::IS_FLOATING_POINT) {
+        Some(quote!(::cpp::TypeKind::FloatingPoint))
+    } else if data.has_flag(flags::IS_INTEGRAL) {
+        // The signedness of `char` depends on the platform
+        if data.size == 1 {
+            Some(quote!(
+                ::cpp::TypeKind::SignedInteger | ::cpp::TypeKind::UnsignedInteger
+            ))
+        } else if data.has_flag(flags::IS_SIGNED) {
+            Some(quote!(::cpp::TypeKind::SignedInteger))
+        } else {
+            Some(quote!(::cpp::TypeKind::UnsignedInteger))
+        }
+    } else {
+        None
+    }
+}
+
+/// Generate a check that the rust type `T` has a kind compatible with the C++ type.
+/// `probe` is an expression of type `CppKind<T>`, in which `kind_of(&value)` can be
+/// used to get the type of a value.
+/// Only the types implementing `cpp::CppTypeKind` are checked, and the check is done at
+/// compile time when the function is instantiated.
+fn check_kind(
+    probe: proc_macro2::TokenStream,
+    data: &MetaData,
+    msg: &str,
+    span: Span,
+) -> proc_macro2::TokenStream {
+    let kinds = match compatible_kinds(data) {
+        Some(kinds) => kinds,
+        None => return quote!(),
+    };
+    quote_spanned! {span=>
+        struct CppKind<T>(::core::marker::PhantomData<T>);
+        impl<T: ::cpp::CppTypeKind> CppKind<T> {
+            const CHECK: () = assert!(matches!(<T as ::cpp::CppTypeKind>::KIND, #kinds), #msg);
+        }
+        // Autoref specialization: types which don't implement the trait are not checked
+        trait CheckKind {
+            fn check_kind(&self) {}
+        }
+        impl<T> CheckKind for &CppKind<T> {}
+        trait CheckKnownKind {
+            fn check_kind(&self);
+        }
+        impl<T: ::cpp::CppTypeKind> CheckKnownKind for CppKind<T> {
+            #[inline(always)]
+            fn check_kind(&self) {
+                #[allow(clippy::let_unit_value)]
+                let () = Self::CHECK;
+            }
+        }
+        fn kind_of<T>(_: &T) -> CppKind<T> {
+            CppKind(::core::marker::PhantomData)
+        }
+        (&#probe).check_kind();
+    }
+}
+
+/// Generate the assertions that the signature of the `CppFunction` type `rust_ty` matches
+/// the `records` of the signature of its C++ type `cpp`, in a constant context
+fn check_function_signature(
+    rust_ty: &proc_macro2::TokenStream,
+    records: &[MetaData],
+    name: &Ident,
+    cpp: &str,
+) -> proc_macro2::TokenStream {
+    let arity = records[0].flags as usize;
+    let arity_msg = format!(
+        "the `CppFunction` of capture `{}` does not have the {} arguments of the C++ type `{}`",
+        name, arity, cpp
+    );
+    let checks = records[..=arity].iter().enumerate().map(|(i, data)| {
+        let MetaData { size, align, .. } = *data;
+        let what = if i == 0 {
+            "the return type".to_owned()
+        } else {
+            format!("the argument {}", i)
+        };
+        let msg = format!(
+            "the size or alignment of {} of the `CppFunction` of capture `{}` does not match \
+             the C++ type `{}` ({} bytes, aligned to {})",
+            what, name, cpp, size, align
+        );
+        quote!(assert!(layouts[#i].0 == #size && layouts[#i].1 == #align, #msg);)
+    });
+    quote! {
+        let layouts = <#rust_ty as ::cpp::CppFunctionSignature>::LAYOUTS;
+        assert!(layouts.len() == #arity + 1, #arity_msg);
+        #(#checks)*
+    }
+}
+
 fn find_all_rust_macro(
     input: syn::parse::ParseStream,
 ) -> Result<Vec<RustInvocation>, syn::parse::Error> {
@@ -168,7 +317,7 @@ fn extract_original_macro(input: &syn::DeriveInput) -> Option<proc_macro2::Token
         }
     }
     let mut f = Finder::default();
-    syn::visit::visit_derive_input(&mut f, &input);
+    syn::visit::visit_derive_input(&mut f, input);
     f.0
 }
 
@@ -183,21 +332,38 @@ pub fn expand_internal(input: proc_macro::TokenStream) -> proc_macro::TokenStrea
     // Parse the macro input
     let input = extract_original_macro(&parse_macro_input!(input as syn::DeriveInput)).unwrap();
 
-    let closure = match syn::parse2::<cpp_common::Closure>(input) {
+    let mut closure = match syn::parse2::<cpp_common::Closure>(input) {
         Ok(x) => x,
         Err(err) => return err.to_compile_error().into(),
     };
+    // The hash depends on the C++ types of the aliases, as in the build script
+    closure.sig.resolve_type_aliases(&TYPE_ALIASES);
+
+    // The build script cannot see the type of a variable, so it needs at least one of them
+    for capture in &closure.sig.captures {
+        if capture.ty.is_none() && capture.cpp.is_none() {
+            let name = &capture.name;
+            let msg = format!(
+                "the C++ type of the capture `{0}` cannot be inferred without a type annotation: \
+                 use `{0}: T` or `{0} as \"cpp_type\"`",
+                name
+            );
+            return syn::Error::new(name.span(), msg).to_compile_error().into();
+        }
+    }
 
     // Get the size data compiled by the build macro
     let size_data = match METADATA.get(&closure.sig.name_hash()) {
+        Some(x) if x[0].has_flag(flags::IS_EXCLUDED) => {
+            return quote!(compile_error! {
+r#"This cpp! macro is in a file excluded by build configuration.
+NOTE: Check the `cpp_build::Config::exclude` and `cpp_build::Config::only` calls in the build script."#})
+            .into();
+        }
         Some(x) => x,
         None => {
             #[cfg(not(feature = "docs-only"))]
-            return quote!(compile_error! {
-r#"This cpp! macro is not found in the library's rust-cpp metadata.
-NOTE: Only cpp! macros found directly in the program source will be parsed -
-NOTE: They cannot be generated by macro expansion."#})
-            .into();
+            return not_found_error("cpp").into();
             #[cfg(feature = "docs-only")]
             {
                 return quote! {
@@ -213,46 +379,139 @@ NOTE: They cannot be generated by macro expansion."#})
     let mut extern_params = Vec::new();
     let mut tt_args = Vec::new();
     let mut call_args = Vec::new();
+    let mut binding_pats = Vec::new();
+    let mut binding_values = Vec::new();
+    // The records of the signatures of the `rustcpp::function` captures follow the captures
+    let mut function_records = size_data[closure.sig.captures.len() + 1..].iter();
     for (i, capture) in closure.sig.captures.iter().enumerate() {
         let written_name = &capture.name;
         let span = written_name.span();
         let mac_name = Ident::new(&format!("var_{}", written_name), span);
         let mac_cty = Ident::new(&format!("cty_{}", written_name), span);
+        let mac_ty = Ident::new(&format!("ty_{}", written_name), span);
+        let mac_expr = Ident::new(&format!("expr_{}", written_name), span);
+        let cpp_name = capture
+            .cpp
+            .as_ref()
+            .map_or_else(|| "auto".to_owned(), ToString::to_string);
+        // A moved value is kept in a `ManuallyDrop`, since the C++ code destroys it
+        let value = if capture.moved {
+            quote_spanned!(span=> *$#mac_name)
+        } else {
+            quote_spanned!(span=> $#mac_name)
+        };
 
         // Generate the assertion to check that the size and align of the types
-        // match before calling.
+        // match. An annotated type is checked by a constant, like the return type.
+        // Otherwise the type of the capture is inferred, so the check is done in an
+        // associated constant of a generic type, which is evaluated at compile time
+        // when the function is instantiated. The error shows the rust type as the
+        // parameter of `CppCapture`.
         let MetaData { size, align, .. } = size_data[i + 1];
-        let sizeof_msg = format!(
-            "size_of for argument `{}` does not match between c++ and \
-             rust",
-            &capture.name
+        let assertion = if let Some(ty) = &capture.ty {
+            let sizeof_msg = format!(
+                "size_of for capture `{}` does not match between the C++ type `{}` ({} bytes) \
+                 and the rust type `{}`",
+                &capture.name,
+                cpp_name,
+                size,
+                quote!(#ty)
+            );
+            let alignof_msg = format!(
+                "align_of for capture `{}` does not match between the C++ type `{}` ({} bytes) \
+                 and the rust type `{}`",
+                &capture.name,
+                cpp_name,
+                align,
+                quote!(#ty)
+            );
+            quote_spanned! {span=>
+                const _: () = {
+                    assert!(::core::mem::size_of::<$#mac_ty>() == #size, #sizeof_msg);
+                    assert!(::core::mem::align_of::<$#mac_ty>() == #align, #alignof_msg);
+                };
+            }
+        } else {
+            // The type is only known to rustc, which shows it in the name of the constant
+            let captured = match &capture.expr {
+                Some(expr) => quote!(#expr).to_string(),
+                None => capture.name.to_string(),
+            };
+            let sizeof_msg = format!(
+                "size_of for capture `{}` does not match between the C++ type `{}` ({} bytes) \
+                 and the rust type of `{}` (the `T` of `CppCapture::<T>`)",
+                &capture.name, cpp_name, size, captured
+            );
+            let alignof_msg = format!(
+                "align_of for capture `{}` does not match between the C++ type `{}` ({} bytes) \
+                 and the rust type of `{}` (the `T` of `CppCapture::<T>`)",
+                &capture.name, cpp_name, align, captured
+            );
+            quote_spanned! {span=>
+                struct CppCapture<T>(::core::marker::PhantomData<T>);
+                impl<T> CppCapture<T> {
+                    const CHECK: () = {
+                        assert!(::core::mem::size_of::<T>() == #size, #sizeof_msg);
+                        assert!(::core::mem::align_of::<T>() == #align, #alignof_msg);
+                    };
+                    #[inline(always)]
+                    fn check(_: &T) {
+                        #[allow(clippy::let_unit_value)]
+                        let () = Self::CHECK;
+                    }
+                }
+                CppCapture::check(&#value);
+            }
+        };
+        let signature_assertion = match &capture.cpp {
+            Some(CppType::Literal(cpp)) if is_function_type(cpp) => {
+                let records: Vec<MetaData> = function_records
+                    .by_ref()
+                    .take(FUNCTION_SIGNATURE_RECORDS)
+                    .copied()
+                    .collect();
+                let rust_ty = if capture.ty.is_some() {
+                    quote_spanned!(span=> $#mac_ty)
+                } else {
+                    quote!(T)
+                };
+                let checks = check_function_signature(&rust_ty, &records, &capture.name, cpp);
+                if capture.ty.is_some() {
+                    quote_spanned!(span=> const _: () = { #checks };)
+                } else {
+                    quote_spanned! {span=>
+                        struct CppFunctionCheck<T>(::core::marker::PhantomData<T>);
+                        impl<T: ::cpp::CppFunctionSignature> CppFunctionCheck<T> {
+                            const CHECK: () = { #checks };
+                            #[inline(always)]
+                            fn check(_: &T) {
+                                #[allow(clippy::let_unit_value)]
+                                let () = Self::CHECK;
+                            }
+                        }
+                        CppFunctionCheck::check(&#value);
+                    }
+                }
+            }
+            _ => quote!(),
+        };
+        let kind_msg = format!(
+            "the rust type of capture `{}` is not of the same kind as the C++ type `{}`",
+            &capture.name, cpp_name
         );
-        let alignof_msg = format!(
-            "align_of for argument `{}` does not match between c++ and \
-             rust",
-            &capture.name
+        let kind_assertion = check_kind(
+            quote_spanned!(span=> kind_of(&#value)),
+            &size_data[i + 1],
+            &kind_msg,
+            span,
         );
-        let assertion = quote_spanned! {span=>
-            // Perform a compile time check that the sizes match. This should be
-            // a no-op.
-            if false {
-                ::core::mem::transmute::<_, [u8; #size]>(
-                    ::core::ptr::read(&$#mac_name));
-            }
-
-            // NOTE: Both of these calls should be dead code in opt builds.
-            assert!(::core::mem::size_of_val(&$#mac_name) == #size,
-                    #sizeof_msg);
-            assert!(::core::mem::align_of_val(&$#mac_name) == #align,
-                    #alignof_msg);
-        };
 
-        let mb_mut = if capture.mutable {
+        let mb_mut = if capture.mutable || capture.moved {
             quote_spanned!(span=> mut)
         } else {
             quote!()
         };
-        let ptr = if capture.mutable {
+        let ptr = if capture.mutable || capture.moved {
             quote_spanned!(span=> *mut)
         } else {
             quote_spanned!(span=> *const)
@@ -262,10 +521,49 @@ NOTE: They cannot be generated by macro expansion."#})
 
         extern_params.push(quote_spanned!(span=> #arg_name : #ptr u8));
 
-        tt_args.push(quote_spanned!(span=> #mb_mut $#mac_name : ident as $#mac_cty : tt));
+        // The pattern must match how the capture was written, with an optional rust type
+        // and a C++ type that is either explicit or inferred.
+        let (ty_arg, type_check) = if capture.ty.is_some() {
+            (
+                quote_spanned!(span=> : $#mac_ty : ty),
+                quote_spanned!(span=> let _: &$#mac_ty = &#value;),
+            )
+        } else {
+            (quote!(), quote!())
+        };
+        let cty_arg = if capture.cpp.is_some() {
+            quote_spanned!(span=> as $#mac_cty : tt)
+        } else {
+            quote_spanned!(span=> $(as auto)?)
+        };
+        // An expression is evaluated and bound to a variable with the name of the parameter
+        let (expr_arg, source) = if capture.expr.is_some() {
+            (
+                quote_spanned!(span=> $#mac_expr : expr =>),
+                quote_spanned!(span=> $#mac_expr),
+            )
+        } else {
+            (quote!(), quote_spanned!(span=> $#mac_name))
+        };
+        let mode_arg = if capture.moved {
+            binding_pats.push(quote_spanned!(span=> mut $#mac_name));
+            binding_values.push(quote_spanned!(span=> ::core::mem::ManuallyDrop::new(#source)));
+            quote_spanned!(span=> move)
+        } else {
+            if capture.expr.is_some() {
+                binding_pats.push(quote_spanned!(span=> #mb_mut $#mac_name));
+                binding_values.push(source);
+            }
+            mb_mut.clone()
+        };
+        tt_args
+            .push(quote_spanned!(span=> #expr_arg #mode_arg $#mac_name : ident #ty_arg #cty_arg));
 
         call_args.push(quote_spanned!(span=> {
+            #type_check
             #assertion
+            { #signature_assertion }
+            { #kind_assertion }
             &#mb_mut $#mac_name as #ptr _ as #ptr u8
         }));
     }
@@ -293,16 +591,43 @@ NOTE: They cannot be generated by macro expansion."#})
         assert!(ret_size == 0, "`void` should have a size of 0!");
         quote! {
             #extern_name(#(#call_args),*);
-            #[cfg_attr(feature = "cargo-clippy", allow(useless_transmute))]
-            ::core::mem::transmute::<(), (#ret_ty)>(())
+            #[allow(clippy::useless_transmute)]
+            { ::core::mem::transmute::<(), (#ret_ty)>(()) }
         }
     } else {
         // static assert that the size and alignement are the same
+        let ret_cpp = &closure.sig.cpp;
+        let ret_sizeof_msg = format!(
+            "size_of for the return type does not match between the C++ type `{}` ({} bytes) \
+             and the rust type `{}`",
+            ret_cpp,
+            ret_size,
+            quote!(#ret_ty)
+        );
+        let ret_alignof_msg = format!(
+            "align_of for the return type does not match between the C++ type `{}` ({} bytes) \
+             and the rust type `{}`",
+            ret_cpp,
+            ret_align,
+            quote!(#ret_ty)
+        );
+        let ret_kind_msg = format!(
+            "the return type `{}` is not of the same kind as the C++ type `{}`",
+            quote!(#ret_ty),
+            ret_cpp
+        );
+        let ret_kind_assertion = check_kind(
+            quote!(CppKind::<#ret_ty>(::core::marker::PhantomData)),
+            &size_data[0],
+            &ret_kind_msg,
+            Span::call_site(),
+        );
         let assert_size = quote! {
-            if false {
-                const _assert_size: [(); #ret_size] = [(); ::core::mem::size_of::<#ret_ty>()];
-                const _assert_align: [(); #ret_align] = [(); ::core::mem::align_of::<#ret_ty>()];
-            }
+            const _: () = {
+                assert!(::core::mem::size_of::<#ret_ty>() == #ret_size, #ret_sizeof_msg);
+                assert!(::core::mem::align_of::<#ret_ty>() == #ret_align, #ret_alignof_msg);
+            };
+            { #ret_kind_assertion }
         };
         if_rust_version!(>= 1.36 { quote!(
             #assert_size
@@ -317,35 +642,47 @@ NOTE: They cannot be generated by macro expansion."#})
         )})
     };
 
+    // All the values are evaluated before they are bound, since a parameter may have the
+    // name of a variable used by the expression of another capture
+    let bindings = if binding_pats.is_empty() {
+        quote!()
+    } else {
+        quote!(let (#(#binding_pats,)*) = (#(#binding_values,)*);)
+    };
+
     let input = proc_macro2::TokenStream::from_iter([closure.body].iter().cloned());
     let rust_invocations = find_all_rust_macro.parse2(input).expect("rust! macro");
-    let init_callbacks = if !rust_invocations.is_empty() {
+    // The callbacks are stored in the table by a static constructor, so that they are
+    // there before the C++ code can possibly call them.
+    let (register_callbacks, init_callbacks) = if !rust_invocations.is_empty() {
         let rust_cpp_callbacks = Ident::new(
             &format!("rust_cpp_callbacks{}", *FILE_HASH),
             Span::call_site(),
         );
         let offset = (flags >> 32) as isize;
         let callbacks: Vec<Ident> = rust_invocations.iter().map(|x| x.id.clone()).collect();
-        quote! {
-            use ::std::sync::Once;
-            static INIT_INVOCATIONS: Once = Once::new();
-            INIT_INVOCATIONS.call_once(|| {
-                // #rust_cpp_callbacks is in fact an array. Since we cannot represent it in rust,
-                // we just are gonna take the pointer to it can offset from that.
-                extern "C" {
-                    #[no_mangle]
-                    static mut #rust_cpp_callbacks: *const ::std::os::raw::c_void;
+        (
+            quote! {
+                unsafe extern "C" fn __cpp_register_callbacks() {
+                    // #rust_cpp_callbacks is in fact an array. Since we cannot represent it in rust,
+                    // we just are gonna take the pointer to it can offset from that.
+                    extern "C" {
+                        static mut #rust_cpp_callbacks: *const ::core::ffi::c_void;
+                    }
+                    let callbacks_array : *mut *const ::core::ffi::c_void =
+                        ::core::ptr::addr_of_mut!(#rust_cpp_callbacks);
+                    let mut offset = #offset;
+                    #(
+                        offset += 1;
+                        *callbacks_array.offset(offset - 1) = #callbacks as *const ::core::ffi::c_void;
+                    )*
                 }
-                let callbacks_array : *mut *const ::std::os::raw::c_void = &mut #rust_cpp_callbacks;
-                let mut offset = #offset;
-                #(
-                    offset += 1;
-                    *callbacks_array.offset(offset - 1) = #callbacks as *const ::std::os::raw::c_void;
-                )*
-            });
-        }
+                ::cpp::__cpp_static_constructor!(__cpp_register_callbacks);
+            },
+            quote!(::cpp::__cpp_lazy_constructor!(__cpp_register_callbacks);),
+        )
     } else {
-        quote!()
+        (quote!(), quote!())
     };
 
     let result = quote! {
@@ -353,10 +690,13 @@ NOTE: They cannot be generated by macro expansion."#})
             #decl
         }
 
+        #register_callbacks
+
         macro_rules! __cpp_closure_impl {
-            (#(#tt_args),*) => {
+            (#(#tt_args),* $(,)?) => {
                 {
                     #init_callbacks
+                    #bindings
                     #call
                 }
             }
@@ -365,6 +705,1029 @@ NOTE: They cannot be generated by macro expansion."#})
     result.into()
 }
 
+/// Generate the `__cpp_rust_callback_impl!` macro which expands the `rust!` macro of a
+/// `cpp!{{ }}` snippet into a function exported with the symbol name used by the C++ code
+#[proc_macro_derive(__cpp_internal_rust_callback)]
+pub fn expand_rust_callback(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = extract_original_macro(&parse_macro_input!(input as syn::DeriveInput)).unwrap();
+    let id = match syn::parse2::<Ident>(input) {
+        Ok(x) => x,
+        Err(err) => return err.to_compile_error().into(),
+    };
+    let symbol = rust_callback_symbol(&id);
+    let result = quote! {
+        macro_rules! __cpp_rust_callback_impl {
+            ($($t:tt)*) => {
+                ::cpp::__cpp_internal!{ @expand_rust_macro [#[export_name = #symbol]] $($t)* }
+            }
+        }
+    };
+    result.into()
+}
+
+/// Implement `Deref` to the base class of a `cpp_class!`, and the downcasts if the base
+/// class is polymorphic. `base_data` is the record of the base class.
+fn expand_class_base(
+    class: &cpp_common::Class,
+    hash: u64,
+    base_data: &MetaData,
+) -> proc_macro2::TokenStream {
+    let class_name = &class.name;
+    let base = class.base.as_ref().unwrap();
+    if base_data.size == 0 {
+        return quote!(compile_error! {
+            "The base class of this cpp_class! is not found. It must be the name of a cpp_class!."
+        });
+    }
+    let upcast_name = Ident::new(&format!("__cpp_upcast_{}", hash), Span::call_site());
+    let downcast_name = Ident::new(&format!("__cpp_downcast_{}", hash), Span::call_site());
+    // There is no DerefMut: writing a whole base object through the mutable reference would
+    // skip the C++ assignment operator, and could overwrite the fields of the derived class
+    // stored in the tail padding of the base, or the object at the same address as an empty
+    // base.
+    let mut result = quote! {
+        impl ::core::ops::Deref for #class_name {
+            type Target = #base;
+            fn deref(&self) -> &#base {
+                unsafe {
+                    extern "C" { fn #upcast_name(d: *mut #class_name) -> *mut #base; }
+                    &*#upcast_name(self as *const #class_name as *mut #class_name)
+                }
+            }
+        }
+        impl #class_name {
+            /// Convert to a mutable reference to the base class
+            ///
+            /// # Safety
+            ///
+            /// The base object must not be overwritten as a whole by rust code, e.g. with
+            /// `*base = ...` or `core::mem::swap`: this would not call the C++ assignment
+            /// operator, and would overwrite the parts of the derived object which share its
+            /// storage. Calling the methods of the base class is fine.
+            pub unsafe fn as_base_mut(&mut self) -> &mut #base {
+                unsafe {
+                    extern "C" { fn #upcast_name(d: *mut #class_name) -> *mut #base; }
+                    &mut *#upcast_name(self)
+                }
+            }
+        }
+    };
+    if base_data.has_flag(flags::IS_POLYMORPHIC) {
+        result = quote! { #result
+            impl #class_name {
+                /// Convert a reference to the base class, if it refers to an object of this
+                /// class (with a `dynamic_cast`)
+                pub fn downcast(base: &#base) -> ::core::option::Option<&Self> {
+                    unsafe {
+                        extern "C" { fn #downcast_name(b: *mut #base) -> *mut #class_name; }
+                        #downcast_name(base as *const #base as *mut #base).as_ref()
+                    }
+                }
+                /// Convert a mutable reference to the base class, if it refers to an object of
+                /// this class (with a `dynamic_cast`)
+                pub fn downcast_mut(base: &mut #base) -> ::core::option::Option<&mut Self> {
+                    unsafe {
+                        extern "C" { fn #downcast_name(b: *mut #base) -> *mut #class_name; }
+                        #downcast_name(base).as_mut()
+                    }
+                }
+            }
+        };
+    }
+    result
+}
+
+/// Generate the implementations of the operator traits derived on a `cpp_class!`, which call
+/// the helper functions generated by the build script. `index_data` is the size of the
+/// elements returned by `operator[]`, which is not checked if `None`.
+fn expand_class_operators(
+    class: &cpp_common::Class,
+    hash: u64,
+    index_data: Option<&MetaData>,
+) -> proc_macro2::TokenStream {
+    let class_name = &class.name;
+    let mut result = quote!();
+    for (derive, method, _, kind) in cpp_common::CLASS_OPERATORS {
+        if !class.derives(derive) {
+            continue;
+        }
+        let tr = Ident::new(derive, Span::call_site());
+        let method = Ident::new(method, Span::call_site());
+        let helper = Ident::new(&format!("__cpp_op_{}_{}", method, hash), Span::call_site());
+        result = match kind {
+            OperatorKind::Unary => quote! { #result
+                impl ::core::ops::#tr for &#class_name {
+                    type Output = #class_name;
+                    fn #method(self) -> #class_name {
+                        unsafe {
+                            extern "C" { fn #helper(a: *const #class_name, r: *mut #class_name); }
+                            let mut result = ::core::mem::MaybeUninit::<#class_name>::uninit();
+                            #helper(self, result.as_mut_ptr());
+                            result.assume_init()
+                        }
+                    }
+                }
+                impl ::core::ops::#tr for #class_name {
+                    type Output = #class_name;
+                    fn #method(self) -> #class_name {
+                        ::core::ops::#tr::#method(&self)
+                    }
+                }
+            },
+            OperatorKind::Binary => quote! { #result
+                impl<'a> ::core::ops::#tr<&'a #class_name> for &#class_name {
+                    type Output = #class_name;
+                    fn #method(self, other: &'a #class_name) -> #class_name {
+                        unsafe {
+                            extern "C" {
+                                fn #helper(a: *const #class_name, b: *const #class_name, r: *mut #class_name);
+                            }
+                            let mut result = ::core::mem::MaybeUninit::<#class_name>::uninit();
+                            #helper(self, other, result.as_mut_ptr());
+                            result.assume_init()
+                        }
+                    }
+                }
+                impl ::core::ops::#tr for #class_name {
+                    type Output = #class_name;
+                    fn #method(self, other: #class_name) -> #class_name {
+                        ::core::ops::#tr::#method(&self, &other)
+                    }
+                }
+            },
+            OperatorKind::Assign => quote! { #result
+                impl<'a> ::core::ops::#tr<&'a #class_name> for #class_name {
+                    fn #method(&mut self, other: &'a #class_name) {
+                        unsafe {
+                            extern "C" { fn #helper(a: *mut #class_name, b: *const #class_name); }
+                            #helper(self, other)
+                        }
+                    }
+                }
+                impl ::core::ops::#tr for #class_name {
+                    fn #method(&mut self, other: #class_name) {
+                        ::core::ops::#tr::#method(self, &other)
+                    }
+                }
+            },
+        };
+    }
+
+    if !class.derives("Index") && !class.derives("IndexMut") {
+        return result;
+    }
+    let (idx, output) = match &class.index {
+        Some(x) => x,
+        None => {
+            return quote! { #result
+                compile_error!("The `Index` and `IndexMut` traits of a cpp_class! need the types \
+                    of the index and of the elements, given with `#[cpp_index(Idx => Output)]`");
+            };
+        }
+    };
+    #[cfg(not(feature = "docs-only"))]
+    {
+        let &MetaData { size, align, .. } = match index_data {
+            Some(x) => x,
+            None => {
+                return quote! { #result
+                    compile_error!("The C++ type of the index of this cpp_class! cannot be \
+                        resolved. Check the build script warnings.");
+                };
+            }
+        };
+        let sizeof_msg = format!(
+            "size_of for the elements of `{}` does not match between the C++ type ({} bytes) \
+             and the rust type `{}`",
+            class_name,
+            size,
+            quote!(#output)
+        );
+        let alignof_msg = format!(
+            "align_of for the elements of `{}` does not match between the C++ type ({} bytes) \
+             and the rust type `{}`",
+            class_name,
+            align,
+            quote!(#output)
+        );
+        result = quote! { #result
+            const _: () = {
+                assert!(::core::mem::size_of::<#output>() == #size, #sizeof_msg);
+                assert!(::core::mem::align_of::<#output>() == #align, #alignof_msg);
+            };
+        };
+    }
+    #[cfg(feature = "docs-only")]
+    let _ = index_data;
+
+    let index_name = Ident::new(&format!("__cpp_index_{}", hash), Span::call_site());
+    result = quote! { #result
+        impl ::core::ops::Index<#idx> for #class_name {
+            type Output = #output;
+            fn index(&self, i: #idx) -> &#output {
+                unsafe {
+                    extern "C" { fn #index_name(a: *const #class_name, i: *const #idx) -> *const #output; }
+                    &*#index_name(self, &i)
+                }
+            }
+        }
+    };
+    if class.derives("IndexMut") {
+        let index_mut_name = Ident::new(&format!("__cpp_index_mut_{}", hash), Span::call_site());
+        result = quote! { #result
+            impl ::core::ops::IndexMut<#idx> for #class_name {
+                fn index_mut(&mut self, i: #idx) -> &mut #output {
+                    unsafe {
+                        extern "C" { fn #index_mut_name(a: *mut #class_name, i: *const #idx) -> *mut #output; }
+                        &mut *#index_mut_name(self, &i)
+                    }
+                }
+            }
+        };
+    }
+    result
+}
+
+/// Check the offsets of the fields declared with `#[cpp_fields(...)]` against the ones of a
+/// `#[repr(C)]` struct, and generate the accessors of the fields which are not private.
+fn expand_class_fields(
+    class_name: &Ident,
+    fields: &[cpp_common::ClassField],
+    fields_data: &[MetaData],
+) -> proc_macro2::TokenStream {
+    let names = fields.iter().map(|f| &f.name);
+    let types = fields.iter().map(|f| &f.ty);
+    let mut checks = vec![];
+    let mut accessors = vec![];
+    for (field, data) in fields.iter().zip(fields_data) {
+        let cpp_common::ClassField { vis, name, ty } = field;
+        // The offset is stored in the size, and the size in the align
+        let MetaData {
+            size: offset,
+            align: size,
+            ..
+        } = *data;
+        let offset_msg = format!(
+            "the offset of the field `{}` of `{}` is {} in C++, which does not match the \
+             declared fields",
+            name, class_name, offset
+        );
+        let size_msg = format!(
+            "size_of for the field `{}` of `{}` does not match between C++ ({} bytes) and \
+             the rust type `{}`",
+            name,
+            class_name,
+            size,
+            quote!(#ty)
+        );
+        checks.push(quote! {
+            assert!(::core::mem::offset_of!(CppFields, #name) == #offset, #offset_msg);
+            assert!(::core::mem::size_of::<#ty>() == #size, #size_msg);
+        });
+        if let syn::Visibility::Inherited = vis {
+            continue;
+        }
+        let name_mut = Ident::new(&format!("{}_mut", name), name.span());
+        accessors.push(quote! {
+            #vis fn #name(&self) -> &#ty {
+                unsafe { &*((self as *const Self as *const u8).add(#offset) as *const #ty) }
+            }
+            #vis fn #name_mut(&mut self) -> &mut #ty {
+                unsafe { &mut *((self as *mut Self as *mut u8).add(#offset) as *mut #ty) }
+            }
+        });
+    }
+    let alignof_msg = format!(
+        "the declared fields of `{}` need a greater alignment than the C++ class",
+        class_name
+    );
+    quote! {
+        const _: () = {
+            #[allow(dead_code)]
+            #[repr(C)]
+            struct CppFields { #(#names: #types,)* }
+            assert!(
+                ::core::mem::align_of::<CppFields>() <= ::core::mem::align_of::<#class_name>(),
+                #alignof_msg
+            );
+            #(#checks)*
+        };
+        impl #class_name {
+            #(#accessors)*
+        }
+    }
+}
+
+/// Generate the API of a `cpp_class!` wrapping a `std::vector` or a smart pointer, which
+/// calls the helper functions generated by the build script for the class.
+fn expand_class_wrapper(
+    class_name: &Ident,
+    hash: u64,
+    wrapper: ClassWrapper,
+    element: &syn::Type,
+    element_data: &MetaData,
+) -> proc_macro2::TokenStream {
+    let MetaData { size, align, .. } = *element_data;
+    let sizeof_msg = format!(
+        "size_of for the elements of `{}` does not match between the C++ type ({} bytes) \
+         and the rust type `{}`",
+        class_name,
+        size,
+        quote!(#element)
+    );
+    let alignof_msg = format!(
+        "align_of for the elements of `{}` does not match between the C++ type ({} bytes) \
+         and the rust type `{}`",
+        class_name,
+        align,
+        quote!(#element)
+    );
+    let assert_size = quote! {
+        const _: () = {
+            assert!(::core::mem::size_of::<#element>() == #size, #sizeof_msg);
+            assert!(::core::mem::align_of::<#element>() == #align, #alignof_msg);
+        };
+    };
+
+    match wrapper {
+        ClassWrapper::Vector => {
+            let len_name = Ident::new(&format!("__cpp_vector_len_{}", hash), Span::call_site());
+            let data_name = Ident::new(&format!("__cpp_vector_data_{}", hash), Span::call_site());
+            let push_name = Ident::new(&format!("__cpp_vector_push_{}", hash), Span::call_site());
+            quote! {
+                #assert_size
+                extern "C" {
+                    fn #len_name(v: *const #class_name) -> usize;
+                    fn #data_name(v: *const #class_name) -> *const #element;
+                    fn #push_name(v: *mut #class_name, value: *mut #element);
+                }
+                impl #class_name {
+                    pub fn len(&self) -> usize {
+                        unsafe { #len_name(self) }
+                    }
+                    pub fn is_empty(&self) -> bool {
+                        self.len() == 0
+                    }
+                    /// Append an element at the end of the vector (with `push_back`)
+                    pub fn push(&mut self, value: #element) {
+                        let mut value = ::core::mem::ManuallyDrop::new(value);
+                        unsafe { #push_name(self, &mut *value) }
+                    }
+                    pub fn as_slice(&self) -> &[#element] {
+                        let len = self.len();
+                        if len == 0 {
+                            return &[];
+                        }
+                        unsafe { ::core::slice::from_raw_parts(#data_name(self), len) }
+                    }
+                    pub fn as_mut_slice(&mut self) -> &mut [#element] {
+                        let len = self.len();
+                        if len == 0 {
+                            return &mut [];
+                        }
+                        unsafe { ::core::slice::from_raw_parts_mut(#data_name(self) as *mut #element, len) }
+                    }
+                }
+                impl ::core::ops::Index<usize> for #class_name {
+                    type Output = #element;
+                    fn index(&self, index: usize) -> &#element {
+                        &self.as_slice()[index]
+                    }
+                }
+                impl ::core::ops::IndexMut<usize> for #class_name {
+                    fn index_mut(&mut self, index: usize) -> &mut #element {
+                        &mut self.as_mut_slice()[index]
+                    }
+                }
+                impl<'a> ::core::iter::IntoIterator for &'a #class_name {
+                    type Item = &'a #element;
+                    type IntoIter = ::core::slice::Iter<'a, #element>;
+                    fn into_iter(self) -> Self::IntoIter {
+                        self.as_slice().iter()
+                    }
+                }
+                impl<'a> ::core::iter::IntoIterator for &'a mut #class_name {
+                    type Item = &'a mut #element;
+                    type IntoIter = ::core::slice::IterMut<'a, #element>;
+                    fn into_iter(self) -> Self::IntoIter {
+                        self.as_mut_slice().iter_mut()
+                    }
+                }
+            }
+        }
+        ClassWrapper::UniquePtr | ClassWrapper::SharedPtr => {
+            let get_name = Ident::new(&format!("__cpp_ptr_get_{}", hash), Span::call_site());
+            let new_name = Ident::new(&format!("__cpp_ptr_new_{}", hash), Span::call_site());
+            // The object of a shared_ptr may be shared, so it cannot be mutably borrowed
+            let deref_mut = if wrapper == ClassWrapper::UniquePtr {
+                quote! {
+                    impl #class_name {
+                        pub fn get_mut(&mut self) -> ::core::option::Option<&mut #element> {
+                            unsafe { (#get_name(self) as *mut #element).as_mut() }
+                        }
+                    }
+                    impl ::core::ops::DerefMut for #class_name {
+                        fn deref_mut(&mut self) -> &mut #element {
+                            self.get_mut().expect("dereference of a null pointer")
+                        }
+                    }
+                }
+            } else {
+                quote!()
+            };
+            quote! {
+                #assert_size
+                extern "C" {
+                    fn #get_name(p: *const #class_name) -> *const #element;
+                    fn #new_name(value: *mut #element, dest: *mut #class_name);
+                }
+                impl #class_name {
+                    /// Allocate a new object, move-constructed from the value
+                    pub fn new(value: #element) -> Self {
+                        let mut value = ::core::mem::ManuallyDrop::new(value);
+                        let mut result = ::core::mem::MaybeUninit::<Self>::uninit();
+                        unsafe {
+                            #new_name(&mut *value, result.as_mut_ptr());
+                            result.assume_init()
+                        }
+                    }
+                    pub fn get(&self) -> ::core::option::Option<&#element> {
+                        unsafe { #get_name(self).as_ref() }
+                    }
+                    pub fn is_null(&self) -> bool {
+                        self.get().is_none()
+                    }
+                }
+                impl ::core::ops::Deref for #class_name {
+                    type Target = #element;
+                    fn deref(&self) -> &#element {
+                        self.get().expect("dereference of a null pointer")
+                    }
+                }
+                #deref_mut
+            }
+        }
+    }
+}
+
+/// Generate the `__cpp_const_impl!` macro which expands to the value of a `cpp_const!`
+/// computed by the build script
+#[proc_macro_derive(__cpp_internal_const)]
+pub fn expand_const(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = extract_original_macro(&parse_macro_input!(input as syn::DeriveInput)).unwrap();
+
+    let c = match ::syn::parse2::<cpp_common::Const>(input) {
+        Ok(x) => x,
+        Err(err) => return err.to_compile_error().into(),
+    };
+
+    let is_bool = matches!(&c.ty, syn::Type::Path(p) if p.path.is_ident("bool"));
+    let value = match METADATA.get(&c.name_hash()) {
+        Some(x) if x[0].has_flag(flags::IS_EXCLUDED) => {
+            return quote!(compile_error! {
+r#"This cpp_const! macro is in a file excluded by build configuration.
+NOTE: Check the `cpp_build::Config::exclude` and `cpp_build::Config::only` calls in the build script."#})
+            .into();
+        }
+        // The value is stored in the size field
+        Some(x) if is_bool => {
+            let value = x[0].size != 0;
+            quote!(#value)
+        }
+        Some(x) if x[0].has_flag(flags::IS_NEGATIVE) => {
+            let value = proc_macro2::Literal::u64_unsuffixed((x[0].size as i64).unsigned_abs());
+            quote!(-#value)
+        }
+        Some(x) => {
+            let value = proc_macro2::Literal::u64_unsuffixed(x[0].size as u64);
+            quote!(#value)
+        }
+        None => {
+            #[cfg(not(feature = "docs-only"))]
+            return not_found_error("cpp_const").into();
+            #[cfg(feature = "docs-only")]
+            {
+                if is_bool {
+                    quote!(false)
+                } else {
+                    quote!(0)
+                }
+            }
+        }
+    };
+
+    // The value is checked against the range of the rust type, as the literal alone would
+    // only trigger the overflowing_literals lint
+    let value = if is_bool {
+        value
+    } else {
+        let ty = &c.ty;
+        let msg = format!(
+            "the value of the C++ expression `{}` does not fit in the rust type `{}`",
+            c.cpp,
+            quote!(#ty)
+        );
+        quote! {{
+            let value: i128 = #value;
+            assert!(value >= <#ty>::MIN as i128 && value <= <#ty>::MAX as i128, #msg);
+            value as #ty
+        }}
+    };
+
+    let result = quote! {
+        macro_rules! __cpp_const_impl {
+            () => { #value };
+        }
+    };
+    result.into()
+}
+
+/// Generate the `__cpp_enum_impl!` macro of a `cpp_enum!`. Given `Variant in <enum>`, it
+/// expands to the value of the enumerator computed by the build script. Given the enum, it
+/// checks that the `#[repr]` matches the underlying type of the C++ enum.
+#[proc_macro_derive(__cpp_internal_enum)]
+pub fn expand_enum(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = extract_original_macro(&parse_macro_input!(input as syn::DeriveInput)).unwrap();
+
+    let parser = |input: syn::parse::ParseStream| -> syn::Result<_> {
+        let variant = if input.peek2(Token![in]) {
+            let variant = input.parse::<Ident>()?;
+            input.parse::<Token![in]>()?;
+            Some(variant)
+        } else {
+            None
+        };
+        // The input is wrapped in brackets by the macro_rules
+        let content;
+        syn::bracketed!(content in input);
+        Ok((variant, content.parse::<cpp_common::Enum>()?))
+    };
+    let (variant, e) = match parser.parse2(input) {
+        Ok(x) => x,
+        Err(err) => return err.to_compile_error().into(),
+    };
+
+    let data = METADATA.get(&e.name_hash());
+    let result = match (variant, data) {
+        // The errors are only reported once, by the check of the enum
+        (Some(_), None) => quote!(0),
+        (Some(_), Some(x)) if x[0].has_flag(flags::IS_EXCLUDED) => quote!(0),
+        (Some(variant), Some(x)) => {
+            let index = e.variants.iter().position(|v| *v == variant).unwrap();
+            let data = &x[index + 1];
+            if data.has_flag(flags::IS_NEGATIVE) {
+                let value = proc_macro2::Literal::u64_unsuffixed((data.size as i64).unsigned_abs());
+                quote!(-#value)
+            } else {
+                let value = proc_macro2::Literal::u64_unsuffixed(data.size as u64);
+                quote!(#value)
+            }
+        }
+        (None, Some(x)) if x[0].has_flag(flags::IS_EXCLUDED) => quote!(compile_error! {
+r#"This cpp_enum! macro is in a file excluded by build configuration.
+NOTE: Check the `cpp_build::Config::exclude` and `cpp_build::Config::only` calls in the build script."#}),
+        (None, Some(x)) => {
+            let repr =
+                e.ty.as_ref()
+                    .map_or_else(|| "i32".to_owned(), |t| t.to_string());
+            let signed = x[0].has_flag(flags::IS_SIGNED);
+            let expected = match (x[0].size, signed) {
+                (1, true) => "i8",
+                (2, true) => "i16",
+                (4, true) => "i32",
+                (8, true) => "i64",
+                (1, false) => "u8",
+                (2, false) => "u16",
+                (4, false) => "u32",
+                (8, false) => "u64",
+                _ => "",
+            };
+            let pointer_sized = match x[0].size * 8 {
+                n if n == usize::BITS as usize && signed => "isize",
+                n if n == usize::BITS as usize => "usize",
+                _ => "",
+            };
+            if repr == expected || repr == pointer_sized {
+                quote!()
+            } else {
+                let msg = format!(
+                    "The underlying type of the C++ enum `{}` is a {}-byte {} integer, which does \
+                     not match the rust type `{}`. Specify it with `enum {}: {} as ...`",
+                    e.cpp,
+                    x[0].size,
+                    if signed { "signed" } else { "unsigned" },
+                    repr,
+                    e.name,
+                    expected
+                );
+                quote!(compile_error! { #msg })
+            }
+        }
+        (None, None) => {
+            #[cfg(not(feature = "docs-only"))]
+            let r = not_found_error("cpp_enum");
+            #[cfg(feature = "docs-only")]
+            let r = quote!();
+            r
+        }
+    };
+
+    let result = quote! {
+        macro_rules! __cpp_enum_impl {
+            () => { #result };
+        }
+    };
+    result.into()
+}
+
+/// Check that the layout of the struct of a `cpp_export_struct!` is the same as the one of
+/// the C++ struct generated by the build script.
+#[proc_macro_derive(__cpp_internal_export_struct)]
+pub fn expand_export_struct(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = extract_original_macro(&parse_macro_input!(input as syn::DeriveInput)).unwrap();
+
+    let parser = |input: syn::parse::ParseStream| -> syn::Result<cpp_common::ExportedStruct> {
+        // The input is wrapped in brackets by the macro_rules
+        let content;
+        syn::bracketed!(content in input);
+        content.parse()
+    };
+    let st = match parser.parse2(input) {
+        Ok(x) => x,
+        Err(err) => return err.to_compile_error().into(),
+    };
+
+    let data = match METADATA.get(&st.name_hash()) {
+        Some(x) => x,
+        None => {
+            #[cfg(not(feature = "docs-only"))]
+            return not_found_error("cpp_export_struct").into();
+            #[cfg(feature = "docs-only")]
+            return quote!().into();
+        }
+    };
+    if data[0].has_flag(flags::IS_EXCLUDED) {
+        return quote!(compile_error! {
+r#"This cpp_export_struct! macro is in a file excluded by build configuration.
+NOTE: Check the `cpp_build::Config::exclude` and `cpp_build::Config::only` calls in the build script."#})
+        .into();
+    }
+
+    let name = &st.name;
+    let MetaData { size, align, .. } = data[0];
+    let sizeof_msg = format!(
+        "size_of for `{}` does not match between the C++ struct `{}` ({} bytes) and the rust struct",
+        name, st.cpp, size
+    );
+    let alignof_msg = format!(
+        "align_of for `{}` does not match between the C++ struct `{}` ({} bytes) and the rust struct",
+        name, st.cpp, align
+    );
+    let fields = st.fields.iter().zip(&data[1..]).map(|(field, data)| {
+        let field_name = &field.name;
+        let ty = &field.ty;
+        // The offset is stored in the size, and the size in the align
+        let MetaData {
+            size: offset,
+            align: size,
+            ..
+        } = *data;
+        let offset_msg = format!(
+            "the offset of the field `{}` of `{}` does not match between C++ ({}) and rust",
+            field_name, name, offset
+        );
+        let size_msg = format!(
+            "size_of for the field `{}` of `{}` does not match between C++ ({} bytes) and rust",
+            field_name, name, size
+        );
+        quote! {
+            assert!(::core::mem::offset_of!(#name, #field_name) == #offset, #offset_msg);
+            assert!(::core::mem::size_of::<#ty>() == #size, #size_msg);
+        }
+    });
+
+    let result = quote! {
+        const _: () = {
+            assert!(::core::mem::size_of::<#name>() == #size, #sizeof_msg);
+            assert!(::core::mem::align_of::<#name>() == #align, #alignof_msg);
+            #(#fields)*
+        };
+    };
+    result.into()
+}
+
+/// Generate the `__cpp_iter_impl!` macro of a `cpp_iter!`, which creates the `CppIter` from
+/// the container, with the functions generated by the build script.
+#[proc_macro_derive(__cpp_internal_iter)]
+pub fn expand_iter(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = extract_original_macro(&parse_macro_input!(input as syn::DeriveInput)).unwrap();
+
+    let it = match syn::parse2::<cpp_common::Iter>(input) {
+        Ok(x) => x,
+        Err(err) => return err.to_compile_error().into(),
+    };
+    let item = &it.item;
+
+    // The container is only borrowed
+    let mut check_mut = &it.item;
+    let mut elements_mut = vec![];
+    loop {
+        match check_mut {
+            syn::Type::Group(g) => check_mut = &g.elem,
+            syn::Type::Paren(p) => check_mut = &p.elem,
+            syn::Type::Tuple(t) => {
+                elements_mut.extend(t.elems.iter());
+                break;
+            }
+            x => {
+                elements_mut.push(x);
+                break;
+            }
+        }
+    }
+    if elements_mut
+        .iter()
+        .any(|t| matches!(t, syn::Type::Reference(r) if r.mutability.is_some()))
+    {
+        return syn::Error::new_spanned(
+            item,
+            "the items of cpp_iter! cannot be mutable references",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    let data = match METADATA.get(&it.name_hash()) {
+        Some(x) if x[0].has_flag(flags::IS_EXCLUDED) => {
+            return quote!(compile_error! {
+r#"This cpp_iter! macro is in a file excluded by build configuration.
+NOTE: Check the `cpp_build::Config::exclude` and `cpp_build::Config::only` calls in the build script."#})
+            .into();
+        }
+        Some(x) => x,
+        None => {
+            #[cfg(not(feature = "docs-only"))]
+            return not_found_error("cpp_iter").into();
+            #[cfg(feature = "docs-only")]
+            return quote! {
+                macro_rules! __cpp_iter_impl {
+                    ($container:tt) => { ::core::iter::empty::<#item>() };
+                }
+            }
+            .into();
+        }
+    };
+
+    let hash = it.name_hash();
+    let MetaData { size, align, .. } = data[0];
+    let base_type = match align {
+        1 => quote!(u8),
+        2 => quote!(u16),
+        4 => quote!(u32),
+        8 => quote!(u64),
+        _ => panic!("unsupported alignment"),
+    };
+    let count = size / align;
+
+    let MetaData {
+        size: container_size,
+        align: container_align,
+        ..
+    } = data[1];
+    let container_sizeof_msg = format!(
+        "size_of for the container of cpp_iter! does not match between the C++ type `{}` \
+         ({} bytes) and the rust type `C`",
+        it.cpp, container_size
+    );
+    let container_alignof_msg = format!(
+        "align_of for the container of cpp_iter! does not match between the C++ type `{}` \
+         ({} bytes) and the rust type `C`",
+        it.cpp, container_align
+    );
+
+    let elements = it.elements();
+    let mut checks = vec![];
+    let mut decls = vec![];
+    let mut outs = vec![];
+    let mut values = vec![];
+    let mut item_types = vec![];
+    for (i, ((ty, by_ref), data)) in elements.iter().zip(&data[2..]).enumerate() {
+        let MetaData { size, align, .. } = *data;
+        let sizeof_msg = format!(
+            "size_of for the element {} of the items of cpp_iter! does not match between \
+             C++ ({} bytes) and the rust type `{}`",
+            i,
+            size,
+            quote!(#ty)
+        );
+        let alignof_msg = format!(
+            "align_of for the element {} of the items of cpp_iter! does not match between \
+             C++ ({} bytes) and the rust type `{}`",
+            i,
+            align,
+            quote!(#ty)
+        );
+        checks.push(quote! {
+            assert!(::core::mem::size_of::<#ty>() == #size, #sizeof_msg);
+            assert!(::core::mem::align_of::<#ty>() == #align, #alignof_msg);
+        });
+        let name = Ident::new(&format!("e{}", i), Span::call_site());
+        if *by_ref {
+            decls.push(quote!(let mut #name = ::core::mem::MaybeUninit::<*const #ty>::uninit();));
+            values.push(quote!(&*#name.assume_init()));
+            item_types.push(quote!(&'a #ty));
+        } else {
+            decls.push(quote!(let mut #name = ::core::mem::MaybeUninit::<#ty>::uninit();));
+            values.push(quote!(#name.assume_init()));
+            item_types.push(quote!(#ty));
+        }
+        outs.push(quote!(#name.as_mut_ptr() as *mut ::core::ffi::c_void));
+    }
+    // The references of the items borrow the container
+    let (item_a, value) = if it.is_tuple() {
+        (quote!((#(#item_types,)*)), quote!((#(#values,)*)))
+    } else {
+        (quote!(#(#item_types)*), quote!(#(#values)*))
+    };
+
+    let begin_name = Ident::new(&format!("__cpp_iter_begin_{}", hash), Span::call_site());
+    let next_name = Ident::new(&format!("__cpp_iter_next_{}", hash), Span::call_site());
+    let drop_name = Ident::new(&format!("__cpp_iter_drop_{}", hash), Span::call_site());
+
+    let result = quote! {
+        macro_rules! __cpp_iter_impl {
+            ($container:tt) => {
+                {
+                    #[repr(C)]
+                    struct CppIterState([#base_type; #count]);
+                    const _: () = { #(#checks)* };
+                    unsafe fn make<'a, C>(container: &'a C) -> ::cpp::CppIter<'a, C, CppIterState, #item_a> {
+                        struct CppContainer<C>(::core::marker::PhantomData<C>);
+                        impl<C> CppContainer<C> {
+                            const CHECK: () = {
+                                assert!(::core::mem::size_of::<C>() == #container_size, #container_sizeof_msg);
+                                assert!(::core::mem::align_of::<C>() == #container_align, #container_alignof_msg);
+                            };
+                        }
+                        #[allow(clippy::let_unit_value)]
+                        let () = CppContainer::<C>::CHECK;
+                        extern "C" {
+                            fn #begin_name(c: *mut ::core::ffi::c_void, s: *mut ::core::ffi::c_void);
+                            fn #next_name(s: *mut ::core::ffi::c_void, out: *mut *mut ::core::ffi::c_void) -> bool;
+                            fn #drop_name(s: *mut ::core::ffi::c_void);
+                        }
+                        unsafe {
+                            let mut state = ::core::mem::MaybeUninit::<CppIterState>::uninit();
+                            #begin_name(
+                                container as *const C as *mut ::core::ffi::c_void,
+                                state.as_mut_ptr() as *mut ::core::ffi::c_void,
+                            );
+                            ::cpp::CppIter::__new(
+                                container,
+                                state.assume_init(),
+                                |s: &mut CppIterState| -> ::core::option::Option<#item_a> {
+                                    #(#decls)*
+                                    let mut out = [#(#outs),*];
+                                    if #next_name(s as *mut CppIterState as *mut ::core::ffi::c_void, out.as_mut_ptr()) {
+                                        ::core::option::Option::Some(#value)
+                                    } else {
+                                        ::core::option::Option::None
+                                    }
+                                },
+                                |s: &mut CppIterState| #drop_name(s as *mut CppIterState as *mut ::core::ffi::c_void),
+                            )
+                        }
+                    }
+                    make(&$container)
+                }
+            };
+        }
+    };
+    result.into()
+}
+
+/// Generate the rust side of a `cpp_impl!`: the constructor of the C++ subclass, and the
+/// functions called by its virtual functions
+#[proc_macro_derive(__cpp_internal_impl)]
+pub fn expand_impl(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = extract_original_macro(&parse_macro_input!(input as syn::DeriveInput)).unwrap();
+
+    let imp = match ::syn::parse2::<cpp_common::Impl>(input) {
+        Ok(x) => x,
+        Err(err) => return err.to_compile_error().into(),
+    };
+
+    let hash = imp.name_hash();
+    match METADATA.get(&hash) {
+        Some(x) if x[0].has_flag(flags::IS_EXCLUDED) => {
+            return quote!(compile_error! {
+r#"This cpp_impl! macro is in a file excluded by build configuration.
+NOTE: Check the `cpp_build::Config::exclude` and `cpp_build::Config::only` calls in the build script."#})
+            .into();
+        }
+        Some(_) => {}
+        None => {
+            #[cfg(not(feature = "docs-only"))]
+            return not_found_error("cpp_impl").into();
+        }
+    }
+
+    let name = &imp.name;
+    let trait_path = &imp.trait_path;
+    let new_name = Ident::new(&format!("__cpp_impl_new_{}", hash), Span::call_site());
+    let delete_name = Ident::new(&format!("__cpp_impl_delete_{}", hash), Span::call_site());
+    let drop_symbol = rust_callback_symbol(&Ident::new(
+        &format!("impl_{}_drop", hash),
+        Span::call_site(),
+    ));
+
+    // Several virtual functions can call the same method, so the callbacks are numbered
+    let methods = imp.methods.iter().enumerate().map(|(index, method)| {
+        let method_name = &method.name;
+        let symbol = rust_callback_symbol(&Ident::new(
+            &format!("impl_{}_method_{}_{}", hash, index, method_name),
+            Span::call_site(),
+        ));
+        let fn_name = Ident::new(&format!("method_{}_{}", index, method_name), Span::call_site());
+        let this = if method.mutable {
+            quote!(let this: &mut dyn #trait_path = &mut **(data as *mut ::cpp::__private::Box<dyn #trait_path>);)
+        } else {
+            quote!(let this: &dyn #trait_path = &**(data as *const ::cpp::__private::Box<dyn #trait_path>);)
+        };
+        let arg_names = method.args.iter().map(|(arg, _, _)| arg).collect::<Vec<_>>();
+        let arg_types = method.args.iter().map(|(_, ty, _)| ty);
+        let indices = 0..method.args.len();
+        let ret = match &method.ret {
+            Some((ty, _)) => quote!(#ty),
+            None => quote!(()),
+        };
+        quote! {
+            #[export_name = #symbol]
+            unsafe extern "C" fn #fn_name(
+                data: *mut ::core::ffi::c_void,
+                args: *const *mut ::core::ffi::c_void,
+                ret: *mut ::core::ffi::c_void,
+            ) {
+                #this
+                #(let #arg_names = (*args.add(#indices) as *mut #arg_types).read();)*
+                (ret as *mut #ret).write(this.#method_name(#(#arg_names),*));
+            }
+        }
+    });
+
+    let result = quote! {
+        impl #name {
+            /// Allocate the C++ object, whose virtual functions call the methods of `imp`
+            pub fn new<T: #trait_path + 'static>(imp: T) -> Self {
+                Self::from_box(::cpp::__private::Box::new(imp))
+            }
+
+            /// Allocate the C++ object, whose virtual functions call the methods of `imp`
+            pub fn from_box(imp: ::cpp::__private::Box<dyn #trait_path>) -> Self {
+                extern "C" {
+                    fn #new_name(imp: *mut ::core::ffi::c_void) -> *mut ::core::ffi::c_void;
+                }
+                let imp = ::cpp::__private::Box::into_raw(::cpp::__private::Box::new(imp));
+                #name {
+                    _ptr: [unsafe { #new_name(imp as *mut ::core::ffi::c_void) }],
+                }
+            }
+
+            /// The pointer to the C++ object, as a pointer to its base class
+            pub fn as_ptr(&self) -> *mut ::core::ffi::c_void {
+                self._ptr[0]
+            }
+
+            /// Give the ownership of the C++ object to the C++ code, which must delete it
+            /// through a pointer to its base class
+            pub fn into_raw(self) -> *mut ::core::ffi::c_void {
+                let this = ::core::mem::ManuallyDrop::new(self);
+                this._ptr[0]
+            }
+        }
+
+        impl ::core::ops::Drop for #name {
+            fn drop(&mut self) {
+                extern "C" {
+                    fn #delete_name(p: *mut ::core::ffi::c_void);
+                }
+                unsafe { #delete_name(self._ptr[0]) }
+            }
+        }
+
+        const _: () = {
+            #[export_name = #drop_symbol]
+            unsafe extern "C" fn drop_impl(data: *mut ::core::ffi::c_void) {
+                ::core::mem::drop(::cpp::__private::Box::from_raw(
+                    data as *mut ::cpp::__private::Box<dyn #trait_path>,
+                ));
+            }
+            #(#methods)*
+        };
+    };
+    result.into()
+}
+
 #[proc_macro_derive(__cpp_internal_class)]
 pub fn expand_wrap_class(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     // Parse the macro input
@@ -380,14 +1743,16 @@ pub fn expand_wrap_class(input: proc_macro::TokenStream) -> proc_macro::TokenStr
 
     // Get the size data compiled by the build macro
     let size_data = match METADATA.get(&hash) {
+        Some(x) if x[0].has_flag(flags::IS_EXCLUDED) => {
+            return quote!(compile_error! {
+r#"This cpp_class! macro is in a file excluded by build configuration.
+NOTE: Check the `cpp_build::Config::exclude` and `cpp_build::Config::only` calls in the build script."#})
+            .into();
+        }
         Some(x) => x,
         None => {
             #[cfg(not(feature = "docs-only"))]
-            return quote!(compile_error! {
-r#"This cpp_class! macro is not found in the library's rust-cpp metadata.
-NOTE: Only cpp_class! macros found directly in the program source will be parsed -
-NOTE: They cannot be generated by macro expansion."#})
-            .into();
+            return not_found_error("cpp_class").into();
             #[cfg(feature = "docs-only")]
             {
                 let mut result = quote! {
@@ -429,6 +1794,8 @@ NOTE: They cannot be generated by macro expansion."#})
                         }
                     };
                 }
+                let operators = expand_class_operators(&class, hash, None);
+                result = quote! { #result #operators };
                 return result.into();
             };
         }
@@ -594,6 +1961,27 @@ NOTE: They cannot be generated by macro expansion."#})
         };
     }
 
+    if let Some((wrapper, element)) = &class.element {
+        let wrapper_impl =
+            expand_class_wrapper(&class_name, hash, *wrapper, element, &size_data[1]);
+        result = quote! { #result #wrapper_impl };
+    }
+
+    let fields_data = &size_data[1 + class.element.is_some() as usize..];
+    if !class.fields.is_empty() {
+        let fields_impl = expand_class_fields(&class_name, &class.fields, fields_data);
+        result = quote! { #result #fields_impl };
+    }
+
+    if class.base.is_some() {
+        let base_impl = expand_class_base(&class, hash, &fields_data[class.fields.len()]);
+        result = quote! { #result #base_impl };
+    }
+
+    let index_data = fields_data.get(class.fields.len() + class.base.is_some() as usize);
+    let operators = expand_class_operators(&class, hash, index_data);
+    result = quote! { #result #operators };
+
     if class.derives("Hash") {
         panic!("Deriving from Hash is not implemented")
     };
@@ -1,5 +1,22 @@
 extern crate cpp_build;
 
+use std::env;
+use std::fs;
+use std::path::Path;
+
 fn main() {
-    cpp_build::build("src/lib.rs");
+    // Generate a file containing a cpp! macro, which is included by src/lib.rs
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(
+        Path::new(&out_dir).join("generated.rs"),
+        r#"fn generated_value() -> i32 {
+    unsafe { cpp!([] -> i32 as "int" { return 42; }) }
+}
+"#,
+    )
+    .unwrap();
+
+    cpp_build::Config::new()
+        .exclude("src/excluded.rs")
+        .build_roots(["src/lib.rs", "tests/integration.rs"]);
 }
@@ -1,5 +1,5 @@
 use super::A;
-use cpp::{cpp, cpp_class};
+use cpp::{cpp, cpp_class, cpp_iter};
 
 #[test]
 fn destructor() {
@@ -34,6 +34,7 @@ fn member_function() {
 }
 
 cpp_class!(pub(crate) unsafe struct B as "B");
+#[allow(clippy::needless_return)]
 impl B {
     fn new(a: i32, b: i32) -> Self {
         unsafe {
@@ -74,6 +75,7 @@ fn simple_class() {
 }
 
 #[test]
+#[allow(clippy::needless_return)]
 fn move_only() {
     cpp_class!(unsafe struct MoveOnly as "MoveOnly");
     impl MoveOnly {
@@ -99,6 +101,311 @@ fn move_only() {
 }
 
 #[test]
+fn move_captures() {
+    cpp! {{
+        struct Tracked {
+            static int alive;
+            int value;
+            Tracked(int value) : value(value) { alive++; }
+            Tracked(Tracked &&other) : value(other.value) { other.value = -1; alive++; }
+            ~Tracked() { alive--; }
+        };
+        int Tracked::alive = 0;
+    }};
+    cpp_class!(unsafe struct Tracked as "Tracked");
+    let make = |value: i32| unsafe {
+        cpp!([value as "int"] -> Tracked as "Tracked" { return Tracked(value); })
+    };
+    let alive = || unsafe { cpp!([] -> i32 as "int" { return Tracked::alive; }) };
+
+    let t = make(42);
+    assert_eq!(alive(), 1);
+    let value = unsafe {
+        cpp!([move t as "Tracked"] -> i32 as "int" {
+            Tracked sink(std::move(t));
+            return sink.value;
+        })
+    };
+    assert_eq!(value, 42);
+    assert_eq!(alive(), 0);
+
+    // The moved object is destroyed by the C++ code even if it is not moved from
+    unsafe {
+        cpp!([make(5) => move t: Tracked] {
+            if (t.value != 5) abort();
+        })
+    };
+    assert_eq!(alive(), 0);
+}
+
+#[test]
+fn same_name_classes() {
+    // The C++ type of a capture is inferred from the name of its rust type, which is
+    // ambiguous here, so the captures of these types have an explicit C++ type
+    mod first {
+        cpp::cpp_class!(pub unsafe struct Same as "std::vector<int>");
+    }
+    mod second {
+        cpp::cpp_class!(pub unsafe struct Same as "std::shared_ptr<int>");
+    }
+    let v = first::Same::default();
+    let p = second::Same::default();
+    let empty = unsafe {
+        cpp!([v: first::Same as "std::vector<int>", p: second::Same as "std::shared_ptr<int>"]
+                -> bool as "bool" {
+            return v.empty() && !p;
+        })
+    };
+    assert!(empty);
+}
+
+cpp! {{
+    #include <vector>
+    #include <memory>
+}}
+
+cpp_class!(unsafe struct VecInt as "std::vector<int>" vector_of i32);
+cpp_class!(#[derive(Default)] unsafe struct VecA as "std::vector<A>" vector_of A);
+cpp_class!(#[derive(Default)] unsafe struct UniqueA as "std::unique_ptr<A>" unique_ptr_of A);
+cpp_class!(pub(crate) unsafe struct SharedInt as "std::shared_ptr<int>" shared_ptr_of i32);
+cpp_class!(#[derive(Default)] unsafe struct VecPairA as "std::vector<std::pair<int, A>>");
+
+cpp! {{
+    struct Particle {
+        int32_t id;
+        double mass;
+        bool active;
+        char name[8];
+    };
+}}
+
+cpp_class!(
+    #[cpp_fields(id: i32, pub(crate) mass: f64, pub active: bool)]
+    #[derive(Default)]
+    unsafe struct Particle as "Particle"
+);
+
+#[test]
+fn class_fields() {
+    let mut p = Particle::default();
+    assert_eq!(*p.mass(), 0.0);
+    *p.mass_mut() = 2.5;
+    *p.active_mut() = true;
+    let mass = unsafe {
+        cpp!([p as "Particle"] -> f64 as "double" {
+            return p.active ? p.mass : -1;
+        })
+    };
+    assert_eq!(mass, 2.5);
+    assert!(*p.active());
+}
+
+cpp! {{
+    struct Vec3 {
+        double v[3];
+        Vec3 operator+(const Vec3 &o) const { return {{v[0] + o.v[0], v[1] + o.v[1], v[2] + o.v[2]}}; }
+        Vec3 operator-(const Vec3 &o) const { return {{v[0] - o.v[0], v[1] - o.v[1], v[2] - o.v[2]}}; }
+        Vec3 operator*(const Vec3 &o) const { return {{v[0] * o.v[0], v[1] * o.v[1], v[2] * o.v[2]}}; }
+        Vec3 operator-() const { return {{-v[0], -v[1], -v[2]}}; }
+        Vec3 &operator+=(const Vec3 &o) { for (int i = 0; i < 3; ++i) v[i] += o.v[i]; return *this; }
+        double &operator[](size_t i) { return v[i]; }
+        const double &operator[](size_t i) const { return v[i]; }
+    };
+}}
+
+cpp_class!(
+    #[derive(Default, Add, Sub, Mul, Neg, AddAssign, Index, IndexMut)]
+    #[cpp_index(usize => f64)]
+    unsafe struct Vec3 as "Vec3"
+);
+
+impl Vec3 {
+    fn new(x: f64, y: f64, z: f64) -> Self {
+        let mut v = Vec3::default();
+        v[0] = x;
+        v[1] = y;
+        v[2] = z;
+        v
+    }
+    fn values(&self) -> [f64; 3] {
+        [self[0], self[1], self[2]]
+    }
+}
+
+#[test]
+fn operators() {
+    let a = Vec3::new(1.0, 2.0, 3.0);
+    let b = Vec3::new(4.0, 5.0, 6.0);
+    assert_eq!((a + b).values(), [5.0, 7.0, 9.0]);
+    assert_eq!((b - a).values(), [3.0, 3.0, 3.0]);
+    assert_eq!((a * b).values(), [4.0, 10.0, 18.0]);
+    assert_eq!((-a).values(), [-1.0, -2.0, -3.0]);
+
+    let mut c = a;
+    c += &b;
+    c += Vec3::new(1.0, 1.0, 1.0);
+    assert_eq!(c.values(), [6.0, 8.0, 10.0]);
+    assert_eq!((c - b).values(), [2.0, 3.0, 4.0]);
+}
+
+cpp! {{
+    struct Named {
+        int id;
+        int get_id() const { return id; }
+        void set_id(int i) { id = i; }
+    };
+    struct Padding { double x; };
+    // The Named base is not at the start of the object
+    struct Item : Padding, Named {};
+
+    struct Shape {
+        virtual ~Shape() {}
+        virtual double area() const { return 0; }
+    };
+    struct Square : Shape {
+        double side;
+        double area() const override { return side * side; }
+    };
+    struct Circle : Shape {
+        double radius;
+        double area() const override { return 3 * radius * radius; }
+    };
+}}
+
+cpp_class!(unsafe struct Named as "Named");
+impl Named {
+    fn id(&self) -> i32 {
+        unsafe { cpp!([self as "const Named*"] -> i32 as "int" { return self->get_id(); }) }
+    }
+    fn set_id(&mut self, id: i32) {
+        unsafe { cpp!([self as "Named*", id as "int"] { self->set_id(id); }) }
+    }
+}
+cpp_class!(#[derive(Default)] unsafe struct Item as "Item" : Named);
+
+cpp_class!(unsafe struct Shape as "Shape");
+impl Shape {
+    fn area(&self) -> f64 {
+        unsafe { cpp!([self as "const Shape*"] -> f64 as "double" { return self->area(); }) }
+    }
+}
+cpp_class!(unsafe struct Square as "Square" : Shape);
+impl Square {
+    fn new(side: f64) -> Self {
+        unsafe {
+            cpp!([side as "double"] -> Square as "Square" {
+                Square s;
+                s.side = side;
+                return s;
+            })
+        }
+    }
+}
+cpp_class!(#[derive(Default)] unsafe struct Circle as "Circle" : Shape);
+
+#[test]
+fn inheritance() {
+    let mut item = Item::default();
+    unsafe { item.as_base_mut() }.set_id(42);
+    assert_eq!(item.id(), 42);
+    let id = unsafe { cpp!([item as "Item"] -> i32 as "int" { return item.id; }) };
+    assert_eq!(id, 42);
+
+    let square = Square::new(3.0);
+    assert_eq!(square.area(), 9.0);
+    let shape: &Shape = &square;
+    assert_eq!(Square::downcast(shape).map(|s| s.area()), Some(9.0));
+    assert!(Circle::downcast(shape).is_none());
+    assert_eq!(Circle::default().area(), 0.0);
+}
+
+#[test]
+fn vector() {
+    let mut v = unsafe { cpp!([] -> VecInt as "std::vector<int>" { return {1, 2, 3}; }) };
+    assert_eq!(v.len(), 3);
+    v.push(4);
+    assert_eq!(v.as_slice(), &[1, 2, 3, 4]);
+    v[0] = 10;
+    for x in &mut v {
+        *x += 1;
+    }
+    assert_eq!((&v).into_iter().sum::<i32>(), 11 + 3 + 4 + 5);
+    let sum = unsafe {
+        cpp!([v as "std::vector<int>"] -> i32 as "int" {
+            int sum = 0;
+            for (int x : v) sum += x;
+            return sum;
+        })
+    };
+    assert_eq!(sum, 23);
+
+    let mut va = VecA::default();
+    assert!(va.is_empty());
+    va.push(A::new(2, 3));
+    va.push(A::new(4, 5));
+    assert_eq!(va.len(), 2);
+    assert_eq!(va[1].multiply(), 20);
+    assert_eq!((&va).into_iter().map(A::multiply).sum::<i32>(), 26);
+}
+
+#[test]
+fn iterators() {
+    let v = unsafe { cpp!([] -> VecInt as "std::vector<int>" { return {1, 2, 3}; }) };
+    assert!(cpp_iter!(unsafe v as "const std::vector<int>&" => i32).eq([1, 2, 3]));
+    let first = cpp_iter!(unsafe v as "const std::vector<int>&" => &i32)
+        .next()
+        .unwrap();
+    assert!(core::ptr::eq(first, &v[0]));
+    let empty = VecInt::default();
+    assert_eq!(
+        cpp_iter!(unsafe empty as "const std::vector<int>&" => i32).count(),
+        0
+    );
+
+    let mut pairs = VecPairA::default();
+    for (k, a, b) in [(3, 1, 2), (1, 3, 4), (2, 5, 6)] {
+        let value = A::new(a, b);
+        unsafe {
+            cpp!([mut pairs as "std::vector<std::pair<int, A>>", k as "int", value as "A"] {
+                pairs.emplace_back(k, value);
+            })
+        };
+    }
+    let by_ref = cpp_iter!(unsafe pairs as "const std::vector<std::pair<int, A>>&" => (i32, &A))
+        .map(|(k, a)| (k, a.multiply()));
+    assert!(by_ref.eq([(3, 2), (1, 12), (2, 30)]));
+    let mut by_value =
+        unsafe { cpp_iter!(pairs as "const std::vector<std::pair<int, A>>&" => (i32, A)) };
+    let (k, a) = by_value.nth(1).unwrap();
+    assert_eq!((k, a.multiply()), (1, 12));
+    // Dropping the iterator early releases the C++ iterator state
+    drop(by_value);
+    assert_eq!(
+        cpp_iter!(unsafe pairs as "const std::vector<std::pair<int, A>>&" => (i32, A)).count(),
+        3
+    );
+}
+
+#[test]
+fn smart_pointers() {
+    let mut p = UniqueA::new(A::new(3, 4));
+    assert_eq!(p.multiply(), 12);
+    p.set_values(5, 6);
+    assert_eq!(p.get().map(A::multiply), Some(30));
+    assert!(UniqueA::default().is_null());
+    assert!(UniqueA::default().get().is_none());
+
+    let s = SharedInt::new(42);
+    let s2 = s.clone();
+    assert_eq!(*s2, 42);
+    let count = unsafe {
+        cpp!([s as "std::shared_ptr<int>"] -> usize as "size_t" { return s.use_count(); })
+    };
+    assert_eq!(count, 2);
+}
+
+#[test]
+#[allow(clippy::clone_on_copy, clippy::nonminimal_bool)]
 fn derive_eq() {
     cpp! {{
         struct WithOpEq {
@@ -122,6 +429,7 @@ fn derive_eq() {
 }
 
 #[test]
+#[allow(clippy::nonminimal_bool)]
 fn derive_ord() {
     cpp! {{
         struct Comp {
@@ -133,7 +441,7 @@ fn derive_ord() {
     }};
     cpp_class!(#[derive(PartialEq, PartialOrd)] #[derive(Eq, Ord)] unsafe struct Comp as "Comp");
     impl Comp {
-        fn new(i: u32) -> Comp {
+        fn new(i: i32) -> Comp {
             unsafe { cpp!([i as "int"] -> Comp as "Comp" { return i; }) }
         }
     }
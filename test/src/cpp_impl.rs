@@ -0,0 +1,110 @@
+use super::{MyTrait, MyTraitImpl, A};
+use cpp::{cpp, cpp_impl};
+use std::cell::Cell;
+use std::rc::Rc;
+
+cpp_impl!(unsafe struct MyClassRust as "MyClass" for dyn MyTrait {
+    computeValue => fn compute_value(&self, x: i32 as "int") -> i32 as "int";
+});
+
+#[test]
+fn virtual_functions() {
+    let object = MyClassRust::new(MyTraitImpl { x: 333 });
+    let i = unsafe {
+        cpp!([object.as_ptr() => object as "MyClass*"] -> i32 as "int" {
+            return operate123(object);
+        })
+    };
+    assert_eq!(i, 123 + 333);
+}
+
+cpp! {{
+    struct Visitor {
+        virtual ~Visitor() {}
+        virtual void visit(const A &a) = 0;
+        virtual A combine(A a, int factor) = 0;
+        virtual int total() const = 0;
+        virtual int size() const = 0;
+    };
+    int visitAll(Visitor *visitor) {
+        visitor->visit(A(1, 2));
+        visitor->visit(A(3, 4));
+        return visitor->combine(A(5, 6), 2).multiply() + visitor->total();
+    }
+}}
+
+pub trait Visit {
+    fn visit(&mut self, a: &A);
+    fn combine(&mut self, a: A, factor: i32) -> A;
+    fn total(&self) -> i32;
+}
+
+struct Summer {
+    total: i32,
+    dropped: Rc<Cell<bool>>,
+}
+
+impl Visit for Summer {
+    fn visit(&mut self, a: &A) {
+        self.total += a.multiply();
+    }
+    fn combine(&mut self, mut a: A, factor: i32) -> A {
+        let product = a.multiply();
+        a.set_values(product * factor, 1);
+        a
+    }
+    fn total(&self) -> i32 {
+        self.total
+    }
+}
+
+impl Drop for Summer {
+    fn drop(&mut self) {
+        self.dropped.set(true);
+    }
+}
+
+// The C++ types are inferred from the rust types
+cpp_impl!(pub(crate) unsafe struct VisitorRust as "Visitor" for dyn Visit {
+    visit => fn visit(&mut self, a: &A);
+    combine => fn combine(&mut self, a: A, factor: i32) -> A;
+    total => fn total(&self) -> i32;
+    size => fn total(&self) -> i32;
+});
+
+#[test]
+fn mutable_methods() {
+    let dropped = Rc::new(Cell::new(false));
+    let visitor = VisitorRust::new(Summer {
+        total: 0,
+        dropped: dropped.clone(),
+    });
+    let r = unsafe {
+        cpp!([visitor.as_ptr() => visitor as "Visitor*"] -> i32 as "int" {
+            return visitAll(visitor);
+        })
+    };
+    assert_eq!(r, 5 * 6 * 2 + 2 + 12);
+    assert!(!dropped.get());
+    drop(visitor);
+    assert!(dropped.get());
+}
+
+#[test]
+fn into_raw() {
+    let dropped = Rc::new(Cell::new(false));
+    let visitor = VisitorRust::new(Summer {
+        total: 0,
+        dropped: dropped.clone(),
+    })
+    .into_raw();
+    let r = unsafe {
+        cpp!([visitor as "Visitor*"] -> i32 as "int" {
+            int r = visitAll(visitor) + visitor->size();
+            delete visitor;
+            return r;
+        })
+    };
+    assert_eq!(r, 5 * 6 * 2 + 2 * (2 + 12));
+    assert!(dropped.get());
+}
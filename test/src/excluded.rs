@@ -0,0 +1,11 @@
+// This module is excluded in the build script: the invalid C++ code below is not compiled,
+// and the cpp! macro reports that it is excluded if it is compiled by rustc.
+
+#[cfg(feature = "excluded_error")]
+pub fn not_compiled() {
+    unsafe { cpp::cpp!([] { this is not C++; }) }
+}
+
+pub fn excluded_value() -> i32 {
+    7
+}
@@ -0,0 +1,30 @@
+// This module is only compiled with the `expansion_error` feature, as the build script warns
+// about the macro invocations it cannot expand, and the included files it cannot read.
+
+// The build script cuts the expression fragments at the commas, so it cannot expand the
+// invocations with a closure taking two arguments. It warns, and builds the other ones.
+macro_rules! cpp_or_apply {
+    (cpp $value:tt) => {
+        unsafe { cpp::cpp!([] -> i32 as "int" { return $value; }) }
+    };
+    (apply $f:expr) => {
+        ($f)(2, 3)
+    };
+    (apply $f:expr, cpp $value:tt) => {
+        ($f)(unsafe { cpp::cpp!([] -> i32 as "int" { return $value; }) }, 3)
+    };
+}
+
+pub fn expanded() -> i32 {
+    cpp_or_apply!(cpp 7) + cpp_or_apply!(apply |a: i32, b: i32| a * b)
+}
+
+// The cpp! macro generated by this invocation is not found. (Its value differs from the one
+// above, which would have the same hash.)
+pub fn not_expanded() -> i32 {
+    cpp_or_apply!(apply |a: i32, b: i32| a * b, cpp 8)
+}
+
+// The build script only warns about a missing file, which rustc reports if it is used
+#[cfg(any())]
+include!("missing.rs");
@@ -0,0 +1,5 @@
+// This file is included with `include!` from lib.rs
+
+fn included_value(x: i32) -> i32 {
+    unsafe { cpp!([x as "int"] -> i32 as "int" { return x * 3; }) }
+}
@@ -1,7 +1,13 @@
 #![recursion_limit = "512"]
+#![cfg_attr(not(feature = "std"), no_std)]
 #![cfg_attr(not(test), allow(dead_code, unused_imports))]
 
-use cpp::{cpp, cpp_class};
+use cpp::{cpp, cpp_class, cpp_const, cpp_enum, cpp_export_struct, cpp_type, DynRef};
+
+// The tests themselves still need std
+#[cfg(all(test, not(feature = "std")))]
+#[macro_use]
+extern crate std;
 
 #[cfg(test)]
 mod inner;
@@ -29,6 +35,17 @@ fn add_two(x: i32) -> i32 {
 
 mod examples;
 
+// Excluded in the build script
+mod excluded;
+
+// Macros which the build script cannot expand or read (see tests/expansion_error.rs)
+#[cfg(feature = "expansion_error")]
+mod expansion_error;
+
+// cpp_impl! requires std
+#[cfg(feature = "std")]
+mod cpp_impl;
+
 cpp! {{
     #define _USE_MATH_DEFINES
     #include <math.h>
@@ -56,6 +73,7 @@ cpp_class!(
     /** More /*comments*/ */
     pub unsafe struct A as "A");
 
+#[allow(clippy::needless_return)]
 impl A {
     fn new(a: i32, b: i32) -> Self {
         unsafe {
@@ -112,7 +130,7 @@ cpp! {{
         double fval = 5.5;
         double res = rust!(xx___8 [fval : f64 as "double"] -> f64 as "double" { fval * 1.2 + 9.9 } );
         if (int((res - (5.5 * 1.2 + 9.9)) * 100000) != 0) return 5;
-        res = rust!(xx___9 [fval : &mut f64 as "double&"] -> f64 as "double" { *fval = *fval * 2.2; 8.8 } );
+        res = rust!(xx___9 [fval : &mut f64 as "double&"] -> f64 as "double" { *fval *= 2.2; 8.8 } );
         if (int((res - (8.8)) * 100000) != 0) return 9;
         if (int((fval - (5.5 * 2.2)) * 100000) != 0) return 10;
         // with a class
@@ -138,6 +156,283 @@ fn captures() {
     assert_eq!(z, 31);
 }
 
+#[test]
+fn inferred_captures() {
+    let x: i32 = 10;
+    let mut y: f64 = 0.5;
+    let p: *const u8 = b"rust\0".as_ptr();
+    let a = A::new(3, 4);
+    let z = unsafe {
+        cpp! {[x: i32, mut y: f64 as auto, p: *const u8, a: A] -> i64 as "long long int" {
+            y *= 2;
+            return x + y + (p[0] == 'r') + a.multiply();
+        }}
+    };
+    assert_eq!(y, 1.0);
+    assert_eq!(z, 24);
+}
+
+#[test]
+fn type_aliases() {
+    let a = A::new(3, 4);
+    let p = &a as *const A;
+    let mut x: i32 = 5;
+    unsafe {
+        cpp! {[p as APtr, mut x as Int] {
+            x += p->multiply();
+        }}
+    };
+    assert_eq!(x, 17);
+    // The hash depends on the C++ types, so this is the same closure
+    unsafe {
+        cpp! {[p as "const A*", mut x as "int"] {
+            x += p->multiply();
+        }}
+    };
+    assert_eq!(x, 29);
+}
+
+cpp! {{
+    #include <limits>
+    #define TEST_MAX 4000000000u
+    enum class Color { Red = -2, Green = 7 };
+    constexpr bool TEST_FLAG = true;
+}}
+
+cpp_const!(const INT_SIZE: usize = "sizeof(int)");
+cpp_const!(pub const TEST_MAX: u32 = "TEST_MAX");
+cpp_const!(pub(crate) const RED: i32 = "Color::Red");
+cpp_const!(const GREEN: u8 = "Color::Green");
+cpp_const!(const TEST_FLAG: bool = "TEST_FLAG");
+cpp_const!(const I64_MIN: i64 = "std::numeric_limits<int64_t>::min()");
+
+#[test]
+#[allow(clippy::bool_assert_comparison)]
+fn constants() {
+    let sizes = [0u8; INT_SIZE];
+    assert_eq!(sizes.len(), 4);
+    assert_eq!(TEST_MAX, 4_000_000_000);
+    assert_eq!(RED, -2);
+    assert_eq!(GREEN, 7);
+    assert_eq!(TEST_FLAG, true);
+    assert_eq!(I64_MIN, i64::MIN);
+}
+
+cpp! {{
+    namespace shapes {
+        enum class Shape { Circle = 1, Square = 4, Triangle = -3 };
+    }
+    enum Size : uint8_t { Small, Large = 200 };
+}}
+
+cpp_enum!(#[derive(Clone, Copy, Debug, PartialEq)] pub enum Shape as "shapes::Shape" {
+    Circle,
+    Square,
+    Triangle,
+});
+cpp_enum!(#[derive(Debug, PartialEq)] enum Size: u8 as "Size" { Small, Large });
+
+#[test]
+fn enums() {
+    use core::convert::TryFrom;
+
+    assert_eq!(Shape::Circle as i32, 1);
+    assert_eq!(Shape::Triangle as i32, -3);
+    assert_eq!(Size::Large as u8, 200);
+    assert_eq!(Shape::try_from(4), Ok(Shape::Square));
+    assert_eq!(Shape::try_from(2), Err(2));
+    assert_eq!(Size::try_from(0u8), Ok(Size::Small));
+    assert_eq!(i32::from(Shape::Circle), 1);
+
+    let shape = Shape::Triangle;
+    let is_triangle = unsafe {
+        cpp!([shape as "shapes::Shape"] -> bool as "bool" {
+            return shape == shapes::Shape::Triangle;
+        })
+    };
+    assert!(is_triangle);
+}
+
+cpp_export_struct! {
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct Point as "geo::Point" {
+        pub x: i32,
+        pub y: f64,
+    }
+}
+
+cpp_export_struct! {
+    struct Segment as "Segment" {
+        flag: u8,
+        from: Point,
+        to: *const Point,
+        name: *const core::ffi::c_char as "const char*",
+    }
+}
+
+cpp_export_struct! {
+    struct Extent as "::geo::Extent" {
+        w: i32,
+        h: i32,
+    }
+}
+
+#[test]
+fn exported_structs() {
+    let from = Point { x: 1, y: 2.5 };
+    let to = Point { x: 4, y: 0.5 };
+    let name = b"segment\0";
+    let segment = Segment {
+        flag: 1,
+        from,
+        to: &to,
+        name: name.as_ptr() as *const core::ffi::c_char,
+    };
+    let r = unsafe {
+        cpp!([segment as "Segment"] -> Point as "geo::Point" {
+            geo::Point r;
+            r.x = segment.to->x - segment.from.x + segment.flag + (segment.name[0] == 's');
+            r.y = segment.to->y + segment.from.y;
+            return r;
+        })
+    };
+    assert_eq!(r, Point { x: 5, y: 3.0 });
+
+    let size = Extent { w: 3, h: 4 };
+    let area = unsafe {
+        cpp!([size as "geo::Extent"] -> i32 as "int" {
+            return size.w * size.h;
+        })
+    };
+    assert_eq!(area, 12);
+}
+
+#[test]
+fn expression_captures() {
+    let a = A::new(3, 4);
+    let buf = [1u8, 2, 3];
+    let values = (5, 6);
+    let z = unsafe {
+        cpp! {[
+            values.0 + 1 => x as "int",
+            a.multiply() => mut y as "int",
+            buf.as_ptr() => data: *const u8,
+            buf.len() => len as "size_t",
+        ] -> i32 as "int" {
+            y *= 2;
+            return x + y + data[len - 1];
+        }}
+    };
+    assert_eq!(z, 33);
+
+    // The expressions are evaluated before the parameters are bound
+    let (x, y) = (1, 2);
+    let swapped = unsafe {
+        cpp!([y => x as "int", x => y as "int"] -> i32 as "int" { return x * 10 + y; })
+    };
+    assert_eq!(swapped, 21);
+}
+
+#[test]
+fn strings_and_slices() {
+    use cpp::{CppString, Slice, SliceMut, StrView};
+
+    let name = "rust";
+    let len = unsafe {
+        cpp!([StrView::from(name) => name as "rustcpp::str_view"] -> usize as "size_t" {
+            std::string s = name;
+            return s == "rust" ? name.size() : 0;
+        })
+    };
+    assert_eq!(len, 4);
+
+    let values = [1, 2, 3];
+    let mut doubled = [0; 3];
+    unsafe {
+        cpp!([
+            Slice::from(&values[..]) => values: Slice<i32>,
+            SliceMut::from(&mut doubled[..]) => doubled: SliceMut<i32> as auto,
+        ] {
+            for (size_t i = 0; i < values.size(); ++i)
+                doubled[i] = values[i] * 2;
+        })
+    };
+    assert_eq!(doubled, [2, 4, 6]);
+
+    let greeting = unsafe {
+        cpp!([StrView::from(name) => name: StrView] -> CppString as "rustcpp::string" {
+            return "hello " + std::string(name);
+        })
+    };
+    assert_eq!(greeting.to_str(), Ok("hello rust"));
+    assert_eq!(&*greeting, b"hello rust");
+    #[cfg(feature = "std")]
+    assert_eq!(greeting.into_string().unwrap(), "hello rust");
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn rust_closures() {
+    use cpp::CppFunction;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::sync::Arc;
+
+    let sum = Arc::new(AtomicI32::new(0));
+    let s = sum.clone();
+    let add = CppFunction::<dyn FnMut(i32, i32) -> i32>::new(move |a, b| {
+        s.fetch_add(a + b, Ordering::Relaxed);
+        a * b
+    });
+    let r = unsafe {
+        cpp!([add as "rustcpp::function<int(int, int)>"] -> i32 as "int" {
+            return add(2, 3) + add(4, 5);
+        })
+    };
+    assert_eq!(r, 6 + 20);
+    assert_eq!(sum.load(Ordering::Relaxed), 14);
+    drop(add);
+    assert_eq!(Arc::strong_count(&sum), 1);
+
+    // The signature of the C++ type is checked against the CppFunction
+    let twice = CppFunction::<dyn FnMut(i64) -> i64>::new(|x| x * 2);
+    let r = unsafe {
+        cpp!([twice: CppFunction<dyn FnMut(i64) -> i64> as "rustcpp::function<int64_t(int64_t)>"]
+                -> i64 as "int64_t" {
+            return twice(21);
+        })
+    };
+    assert_eq!(r, 42);
+
+    // The closure is owned by the std::function, and dropped with it. It is not Send, and
+    // the C++ code only calls it on this thread.
+    let total = Rc::new(Cell::new(0));
+    let t = total.clone();
+    let record = unsafe { CppFunction::<dyn FnMut(A)>::new_local(move |a: A| t.set(a.multiply())) };
+    unsafe {
+        cpp!([move record: CppFunction<dyn FnMut(A)>] {
+            std::function<void(A)> f = std::move(record);
+            std::function<void(A)> copy = f;
+            copy(A(6, 7));
+        })
+    };
+    assert_eq!(total.get(), 42);
+    assert_eq!(Rc::strong_count(&total), 1);
+
+    let make = CppFunction::<dyn FnMut() -> A>::new(|| A::new(3, 4));
+    let a = unsafe {
+        cpp!([make: CppFunction<dyn FnMut() -> A>] -> A as "A" {
+            return make();
+        })
+    };
+    assert_eq!(a.multiply(), 12);
+}
+
+// The aliases can be declared after their use
+cpp_type!(APtr = "const A*");
+cpp_type!(Int = "int");
+
 #[test]
 fn no_captures() {
     cpp! {unsafe [] { global_int = 33; }};
@@ -222,6 +517,7 @@ fn test_nomod() {
 }
 
 #[test]
+#[allow(clippy::bool_assert_comparison)]
 fn rust_submacro() {
     let result = unsafe { cpp!([] -> i32 as "int" { return callRust1(45); }) };
     assert_eq!(result, 47); // callRust1 adds 2
@@ -247,7 +543,7 @@ fn rust_submacro() {
     assert!(result);
 
     let result = unsafe {
-        cpp!([]->u32 as "int" {
+        cpp!([]-> i32 as "int" {
             return manyOtherTest();
         })
     };
@@ -263,15 +559,13 @@ cpp! {{
         virtual int computeValue(int) const = 0;
     };
     int operate123(MyClass *callback) { return callback->computeValue(123); }
-
-    struct TraitPtr { void *a,*b; };
 }}
 cpp! {{
     class MyClassImpl : public MyClass {
       public:
-        TraitPtr m_trait;
+        rustcpp::dyn_ref m_trait;
         int computeValue(int x) const /*override*/ {
-           return rust!(MCI_computeValue [m_trait : &dyn MyTrait as "TraitPtr", x : i32 as "int"]
+           return rust!(MCI_computeValue [m_trait : DynRef<dyn MyTrait> as "rustcpp::dyn_ref", x : i32 as "int"]
                -> i32 as "int" {
                m_trait.compute_value(x)
            });
@@ -292,8 +586,9 @@ impl MyTrait for MyTraitImpl {
 fn rust_submacro_trait() {
     let inst = MyTraitImpl { x: 333 };
     let inst_ptr: &dyn MyTrait = &inst;
+    let inst_ptr = DynRef::new(inst_ptr);
     let i = unsafe {
-        cpp!([inst_ptr as "TraitPtr"] -> u32 as "int" {
+        cpp!([inst_ptr: DynRef<dyn MyTrait>] -> i32 as "int" {
             MyClassImpl mci;
             mci.m_trait = inst_ptr;
             return operate123(&mci);
@@ -304,9 +599,9 @@ fn rust_submacro_trait() {
 
 #[test]
 fn witin_macro() {
-    assert_eq!(unsafe { cpp!([] -> u32 as "int" { return 12; }) }, 12);
+    assert_eq!(unsafe { cpp!([] -> i32 as "int" { return 12; }) }, 12);
     let s = format!("hello{}", unsafe {
-        cpp!([] -> u32 as "int" { return 14; })
+        cpp!([] -> i32 as "int" { return 14; })
     });
     assert_eq!(s, "hello14");
 }
@@ -315,7 +610,7 @@ fn witin_macro() {
 fn with_unsafe() {
     let x = 45;
     assert_eq!(
-        cpp!(unsafe [x as "int"] -> u32 as "int" { return x + 1; }),
+        cpp!(unsafe [x as "int"] -> i32 as "int" { return x + 1; }),
         46
     );
 }
@@ -340,4 +635,71 @@ fn rust_submacro_closure() {
     assert_eq!(result, 18);
 }
 
+// cpp! macros generated by a macro_rules! macro
+macro_rules! cpp_functions {
+    ($name:ident, $value:tt) => {
+        fn $name(x: i32) -> i32 {
+            unsafe { cpp!([x as "int"] -> i32 as "int" { return x + $value; }) }
+        }
+    };
+    ($($name:ident -> $ty:ty as $cpp:tt = $value:expr),*) => {
+        $(fn $name() -> $ty {
+            unsafe { cpp!([] -> $ty as $cpp {
+                #ifdef __cplusplus
+                return $value;
+                #endif
+            }) }
+        })*
+    };
+}
+cpp_functions!(add_three, 3);
+cpp_functions!(add_four, 4);
+cpp_functions!(get_five -> u32 as "unsigned int" = 5, get_half -> f64 as "double" = 0.5);
+
+#[test]
+fn macro_rules_expansion() {
+    assert_eq!(add_three(10), 13);
+    assert_eq!(add_four(10), 14);
+    assert_eq!(get_five(), 5);
+    assert_eq!(get_half(), 0.5);
+
+    // The expression is in an invisible group for the macro, but not for the build script
+    macro_rules! cpp_double {
+        ($e:expr) => {
+            unsafe { cpp!([$e => x as "int"] -> i32 as "int" { return x * 2; }) }
+        };
+    }
+    assert_eq!(cpp_double!(20 + 1), 42);
+
+    // The fragments are also in invisible groups within the captures and their types
+    macro_rules! cpp_last {
+        ($e:expr, $t:ty) => {
+            unsafe {
+                cpp!([$e.len() => n as "size_t", ($e).as_ptr() => p: *const $t as "const int32_t*"]
+                        -> i32 as "int" {
+                    return p[n - 1];
+                })
+            }
+        };
+    }
+    let a = [1, 2, 3];
+    assert_eq!(cpp_last!(a, i32), 3);
+    assert_eq!(cpp_last!(&a[..2], i32), 2);
+}
+
+// cpp! macros in included files
+include!("included.rs");
+include!(concat!(env!("OUT_DIR"), "/generated.rs"));
+
+#[test]
+fn included_files() {
+    assert_eq!(included_value(5), 15);
+    assert_eq!(generated_value(), 42);
+}
+
+#[test]
+fn excluded_module() {
+    assert_eq!(excluded::excluded_value(), 7);
+}
+
 pub mod cpp_class;
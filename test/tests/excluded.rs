@@ -0,0 +1,21 @@
+// Build the library with the `excluded_error` feature, which enables a cpp! macro in the
+// module excluded by the build script: its expansion must report the exclusion.
+
+use std::process::Command;
+
+#[test]
+fn excluded_macro_error() {
+    let output = Command::new(env!("CARGO"))
+        .args(["check", "--lib", "--features", "excluded_error"])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .env("CARGO_TARGET_DIR", env!("CARGO_TARGET_TMPDIR"))
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success(), "{}", stderr);
+    assert!(
+        stderr.contains("This cpp! macro is in a file excluded by build configuration."),
+        "{}",
+        stderr
+    );
+}
@@ -0,0 +1,39 @@
+// Build the library with the `expansion_error` feature, which enables macro_rules! invocations
+// that the build script cannot expand: it must warn about them, and the cpp! macro they
+// generate must name them in its error. The module also includes a missing file.
+
+use std::process::Command;
+
+#[test]
+fn expansion_error() {
+    let output = Command::new(env!("CARGO"))
+        .args(["check", "--lib", "--features", "expansion_error"])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .env("CARGO_TARGET_DIR", env!("CARGO_TARGET_TMPDIR"))
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success(), "{}", stderr);
+    assert!(
+        stderr.contains("Cannot expand `cpp_or_apply!` to find the cpp! macros it contains"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("Cannot read the file `src/missing.rs` included by `include!`"),
+        "{}",
+        stderr
+    );
+    // Only the cpp! macro of the invocation which cannot be expanded is missing
+    assert_eq!(
+        stderr.matches("This cpp! macro is not found").count(),
+        1,
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("NOTE: src/expansion_error.rs:25: Cannot expand `cpp_or_apply!`"),
+        "{}",
+        stderr
+    );
+}
@@ -0,0 +1,22 @@
+// An integration test using cpp! directly. Its crate root is given to cpp_build
+// together with the library's.
+
+use cpp::cpp;
+
+// The generated C++ library is linked with the library crate
+extern crate cpp_test;
+
+cpp! {{
+    int integration_value() { return 12; }
+}}
+
+#[test]
+fn integration_test_closure() {
+    let x: i32 = 30;
+    let r = unsafe {
+        cpp!([x as "int"] -> i32 as "int" {
+            return x + integration_value();
+        })
+    };
+    assert_eq!(r, 42);
+}